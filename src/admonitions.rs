@@ -0,0 +1,178 @@
+use anyhow::{anyhow, Result};
+use pulldown_cmark::{Event, Tag, TagEnd};
+use tera::Tera;
+
+use crate::source_map::SourceMap;
+
+/// The template admonitions render through. Sites can override it by adding
+/// their own `templates/macros/admonition.html`.
+pub(crate) const ADMONITION_TEMPLATE: &str = "macros/admonition.html";
+
+pub(crate) const DEFAULT_ADMONITION_TEMPLATE: &str = r#"<div class="admonition admonition-{{ kind }}">
+<p class="admonition-title">{{ title | safe }}</p>
+{{ body | safe }}</div>
+"#;
+
+/// Rewrites `:::kind Title` ... `:::` blocks into the blockquote form,
+/// `> [!kind] Title`, so both forms render the same way. Also returns where
+/// the new Markdown came from.
+pub(crate) fn expand_fenced(markdown: &str) -> (String, SourceMap) {
+    let mut output = String::new();
+    let mut map = SourceMap::default();
+    let mut depth = 0;
+    let mut code_fence: Option<&str> = None;
+    let mut line_start = 0;
+
+    for line in markdown.split_inclusive('\n') {
+        let trimmed = line.trim();
+        let prefix = "> ".repeat(depth);
+        let start = line_start;
+        line_start += line.len();
+        let mut copy_line = |output: &mut String| {
+            map.replaced(output.len(), start);
+            output.push_str(&prefix);
+            map.copied(output.len(), start);
+            output.push_str(line);
+        };
+
+        // Leave code blocks alone, so they can show `:::` literally.
+        if let Some(fence) = code_fence {
+            if trimmed.starts_with(fence) {
+                code_fence = None;
+            }
+            copy_line(&mut output);
+            continue;
+        }
+        if let Some(fence) = ["```", "~~~"].into_iter().find(|f| trimmed.starts_with(f)) {
+            code_fence = Some(fence);
+            copy_line(&mut output);
+            continue;
+        }
+
+        match trimmed.strip_prefix(":::") {
+            Some("") if depth > 0 => {
+                depth -= 1;
+                // Keep any enclosing admonitions open across the closing fence.
+                map.replaced(output.len(), start);
+                output.push_str(&"> ".repeat(depth));
+                output.push('\n');
+            }
+            Some(opening) if !opening.is_empty() => {
+                let (kind, title) = opening
+                    .trim()
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((opening.trim(), ""));
+                map.replaced(output.len(), start);
+                output.push_str(&format!("{prefix}> [!{kind}] {}\n", title.trim()));
+                depth += 1;
+            }
+            _ => copy_line(&mut output),
+        }
+    }
+    (output, map)
+}
+
+/// Parses the `[!kind] Title` marker that starts an admonition. The title is
+/// only the title's leading text; formatting in it follows as separate events.
+fn parse_marker(text: &str) -> Option<(String, String)> {
+    let rest = text.strip_prefix("[!")?;
+    let (kind, title) = rest.split_once(']')?;
+    if kind.is_empty() || !kind.chars().all(|c| c.is_alphanumeric() || c == '-') {
+        return None;
+    }
+    Some((kind.to_lowercase(), title.trim_start().to_string()))
+}
+
+/// Turns `note` into `Note`, for admonitions without a title.
+fn default_title(kind: &str) -> String {
+    let mut chars = kind.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Replaces blockquotes that start with `[!kind]`, like `> [!WARNING] Careful`,
+/// with the admonition template. The rest of the marker's line is the title,
+/// passed to the template as HTML.
+pub(crate) fn render_admonitions<'a>(
+    events: Vec<Event<'a>>,
+    tera: &Tera,
+) -> Result<Vec<Event<'a>>> {
+    let mut transformed = Vec::with_capacity(events.len());
+    let mut i = 0;
+    while i < events.len() {
+        let marker = match (&events[i], events.get(i + 1), events.get(i + 2)) {
+            (
+                Event::Start(Tag::BlockQuote(_)),
+                Some(Event::Start(Tag::Paragraph)),
+                Some(Event::Text(text)),
+            ) => parse_marker(text),
+            _ => None,
+        };
+        let Some((kind, title)) = marker else {
+            transformed.push(events[i].clone());
+            i += 1;
+            continue;
+        };
+
+        // The title runs to the end of the marker's line.
+        let mut title_events = vec![Event::Text(title.into())];
+        let mut j = i + 3;
+        while let Some(event) = events.get(j) {
+            match event {
+                Event::SoftBreak | Event::HardBreak => {
+                    j += 1;
+                    break;
+                }
+                Event::End(TagEnd::Paragraph) => break,
+                _ => title_events.push(event.clone()),
+            }
+            j += 1;
+        }
+        let mut title = String::new();
+        pulldown_cmark::html::push_html(&mut title, title_events.into_iter());
+        let title = title.trim();
+
+        // Collect the rest of the blockquote, which is the admonition's body.
+        let mut body = vec![Event::Start(Tag::Paragraph)];
+        let mut depth = 0;
+        while j < events.len() {
+            match &events[j] {
+                Event::Start(Tag::BlockQuote(_)) => depth += 1,
+                Event::End(TagEnd::BlockQuote(_)) if depth == 0 => break,
+                Event::End(TagEnd::BlockQuote(_)) => depth -= 1,
+                _ => {}
+            }
+            body.push(events[j].clone());
+            j += 1;
+        }
+        // Drop the paragraph the marker was in if nothing else was in it.
+        if matches!(body.get(1), Some(Event::End(TagEnd::Paragraph))) {
+            body.drain(..2);
+        }
+
+        let body = render_admonitions(body, tera)?;
+        let mut body_html = String::new();
+        pulldown_cmark::html::push_html(&mut body_html, body.into_iter());
+
+        let mut context = tera::Context::new();
+        context.insert(
+            "title",
+            &if title.is_empty() {
+                default_title(&kind)
+            } else {
+                title.to_string()
+            },
+        );
+        context.insert("kind", &kind);
+        context.insert("body", &body_html);
+        let html = tera
+            .render(ADMONITION_TEMPLATE, &context)
+            .map_err(|err| anyhow!("Failed to render admonition '{kind}': {err:?}"))?;
+        transformed.push(Event::Html(html.into()));
+
+        i = j + 1;
+    }
+    Ok(transformed)
+}
@@ -0,0 +1,538 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::html::decode_entities;
+
+/// A problem found in the built site, on the page at `page`, relative to the
+/// site root.
+#[derive(Debug)]
+pub(crate) struct Problem {
+    pub(crate) page: PathBuf,
+    pub(crate) message: String,
+}
+
+/// An HTML start tag, with its attribute values decoded.
+struct HtmlTag {
+    name: String,
+    attributes: Vec<(String, String)>,
+}
+
+impl HtmlTag {
+    fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Finds the start tags in `html`. This isn't a full HTML parser, but it's
+/// enough for the markup Pushpin and its templates write.
+fn start_tags(html: &str) -> Vec<HtmlTag> {
+    let mut tags = vec![];
+    let mut rest = html;
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open + 1..];
+        if let Some(comment) = rest.strip_prefix("!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        if !rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            continue;
+        }
+
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = &rest[name_end..];
+
+        let mut attributes = vec![];
+        loop {
+            rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(after) = rest.strip_prefix('>') {
+                rest = after;
+                break;
+            }
+            let key_end = rest
+                .find(|c: char| c.is_whitespace() || c == '=' || c == '>' || c == '/')
+                .unwrap_or(rest.len())
+                .max(1);
+            let key = rest[..key_end].to_ascii_lowercase();
+            rest = rest[key_end..].trim_start();
+
+            let mut value = String::new();
+            if let Some(after) = rest.strip_prefix('=') {
+                rest = after.trim_start();
+                let (raw, after) = match rest.chars().next() {
+                    Some(quote @ ('"' | '\'')) => {
+                        let end = rest[1..].find(quote).map_or(rest.len(), |end| end + 1);
+                        (&rest[1..end], rest.get(end + 1..).unwrap_or(""))
+                    }
+                    _ => {
+                        let end = rest
+                            .find(|c: char| c.is_whitespace() || c == '>')
+                            .unwrap_or(rest.len());
+                        (&rest[..end], &rest[end..])
+                    }
+                };
+                value = decode_entities(raw);
+                rest = after;
+            }
+            attributes.push((key, value));
+        }
+
+        // The contents of scripts and styles aren't markup.
+        if name == "script" || name == "style" {
+            let close = format!("</{name}");
+            rest = rest.find(&close).map_or("", |end| &rest[end..]);
+        }
+        tags.push(HtmlTag { name, attributes });
+    }
+    tags
+}
+
+/// Decodes `%XX` escapes in a URL path.
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A URL on the site, resolved to a path relative to the site root.
+struct LocalUrl {
+    path: PathBuf,
+    fragment: Option<String>,
+}
+
+/// Resolves `url`, found on the page at `page`, to a path relative to the
+/// site root, which is served from `base_path`, like `/blog/`. Returns `None`
+/// for links off the site, like `https://` or `mailto:` links, and
+/// `Some(None)` for links above the site root.
+fn resolve_url(url: &str, page: &Path, base_path: &str) -> Option<Option<LocalUrl>> {
+    let url = url.trim();
+    let scheme_end = url.find(':');
+    let path_start = url.find(['/', '?', '#']);
+    if url.starts_with("//")
+        || scheme_end.is_some_and(|colon| path_start.is_none_or(|start| colon < start))
+    {
+        return None;
+    }
+
+    let (url, fragment) = match url.split_once('#') {
+        Some((url, fragment)) => (url, Some(percent_decode(fragment))),
+        None => (url, None),
+    };
+    let url = url.split('?').next().unwrap_or("");
+    let path = if url.is_empty() {
+        page.to_path_buf()
+    } else if url.starts_with('/') {
+        match url.strip_prefix(base_path) {
+            Some(from_root) => PathBuf::from(percent_decode(from_root)),
+            None if format!("{url}/") == base_path => PathBuf::new(),
+            None => return Some(None),
+        }
+    } else {
+        page.parent()
+            .unwrap_or(Path::new(""))
+            .join(percent_decode(url))
+    };
+
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir if !normalized.pop() => return Some(None),
+            _ => {}
+        }
+    }
+    if url.ends_with('/') || normalized.as_os_str().is_empty() {
+        normalized.push("index.html");
+    }
+    Some(Some(LocalUrl {
+        path: normalized,
+        fragment,
+    }))
+}
+
+/// Finds every HTML file under `dir`, relative to `root`.
+fn html_files(dir: &Path, root: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(html_files(&path, root)?);
+        } else if path.extension().and_then(|s| s.to_str()) == Some("html") {
+            files.push(path.strip_prefix(root)?.to_path_buf());
+        }
+    }
+    Ok(files)
+}
+
+/// Collects the `http` and `https` URLs linked or loaded from each page in
+/// `output_dir`, with the pages they're on.
+pub(crate) fn outbound_urls(output_dir: &Path) -> Result<BTreeMap<String, BTreeSet<PathBuf>>> {
+    let mut urls: BTreeMap<String, BTreeSet<PathBuf>> = BTreeMap::new();
+    for page in html_files(output_dir, output_dir)? {
+        let html = fs::read_to_string(output_dir.join(&page))?;
+        for tag in start_tags(&html) {
+            let url = match tag.name.as_str() {
+                "a" | "link" => tag.attribute("href"),
+                "img" | "script" => tag.attribute("src"),
+                _ => None,
+            };
+            if let Some(url) =
+                url.filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+            {
+                urls.entry(url.to_string())
+                    .or_default()
+                    .insert(page.clone());
+            }
+        }
+    }
+    Ok(urls)
+}
+
+/// Checks the site built into `output_dir`, to be served from `base_path`, for
+/// broken links and missing assets, duplicate heading IDs and pages nothing
+/// links to.
+///
+/// Links and assets can point at files Pushpin generated or at static files in
+/// `site_root`, like stylesheets and images. Only generated HTML files count as
+/// pages, so a stale page left in the site root doesn't hide a broken link.
+pub(crate) fn check_site(
+    output_dir: &Path,
+    site_root: &Path,
+    base_path: &str,
+) -> Result<Vec<Problem>> {
+    let mut pages = BTreeMap::new();
+    for page in html_files(output_dir, output_dir)? {
+        let html = fs::read_to_string(output_dir.join(&page))?;
+        pages.insert(page, start_tags(&html));
+    }
+    let exists = |path: &Path| {
+        if path.extension().and_then(|s| s.to_str()) == Some("html") {
+            pages.contains_key(path)
+        } else {
+            output_dir.join(path).exists() || site_root.join(path).exists()
+        }
+    };
+
+    let ids: HashMap<&PathBuf, HashSet<&str>> = pages
+        .iter()
+        .map(|(page, tags)| {
+            let ids = tags.iter().filter_map(|tag| tag.attribute("id")).collect();
+            (page, ids)
+        })
+        .collect();
+
+    let mut problems = vec![];
+    let mut linked_pages = HashSet::new();
+    for (page, tags) in &pages {
+        let mut report = |message: String| {
+            problems.push(Problem {
+                page: page.clone(),
+                message,
+            })
+        };
+
+        let mut heading_ids = HashSet::new();
+        for tag in tags {
+            if matches!(tag.name.as_str(), "h1" | "h2" | "h3" | "h4" | "h5" | "h6") {
+                if let Some(id) = tag.attribute("id") {
+                    if !heading_ids.insert(id) {
+                        report(format!("duplicate heading ID `{id}`"));
+                    }
+                }
+            }
+
+            let (url, is_link) = match (
+                tag.name.as_str(),
+                tag.attribute("href"),
+                tag.attribute("src"),
+            ) {
+                ("a", Some(href), _) => (href, true),
+                ("link", Some(href), _) | ("img", _, Some(href)) => (href, false),
+                _ => continue,
+            };
+            let kind = if is_link { "link" } else { "asset" };
+            let Some(resolved) = resolve_url(url, page, base_path) else {
+                continue;
+            };
+            let Some(LocalUrl { path, fragment }) = resolved else {
+                report(format!("broken {kind} `{url}`: it's outside the site"));
+                continue;
+            };
+
+            if !exists(&path) && !exists(&path.join("index.html")) {
+                if is_link {
+                    report(format!(
+                        "broken link `{url}`: there's no `{}`",
+                        path.display()
+                    ));
+                } else {
+                    report(format!(
+                        "missing asset `{url}`: there's no `{}`",
+                        path.display()
+                    ));
+                }
+                continue;
+            }
+            let path = if pages.contains_key(&path) {
+                path
+            } else {
+                path.join("index.html")
+            };
+            if let (Some(fragment), Some(target_ids)) = (&fragment, ids.get(&path)) {
+                if !fragment.is_empty() && !target_ids.contains(fragment.as_str()) {
+                    report(format!(
+                        "broken link `{url}`: there's no `#{fragment}` in `{}`",
+                        path.display()
+                    ));
+                }
+            }
+            if is_link && &path != page {
+                linked_pages.insert(path);
+            }
+        }
+    }
+
+    for page in pages.keys() {
+        if page != Path::new("index.html") && !linked_pages.contains(page) {
+            problems.push(Problem {
+                page: page.clone(),
+                message: "orphaned page: no other page links to it".to_string(),
+            });
+        }
+    }
+    problems.sort_by(|a, b| a.page.cmp(&b.page));
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the name and attributes of each start tag in `html`.
+    fn tags(html: &str) -> Vec<(String, Vec<(String, String)>)> {
+        start_tags(html)
+            .into_iter()
+            .map(|tag| (tag.name, tag.attributes))
+            .collect()
+    }
+
+    fn tag(name: &str, attributes: &[(&str, &str)]) -> (String, Vec<(String, String)>) {
+        let attributes = attributes
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        (name.to_string(), attributes)
+    }
+
+    /// Resolves `url` on the page at `page`, for a site served from `base_path`,
+    /// to its path and fragment.
+    fn resolve(url: &str, page: &str, base_path: &str) -> Option<Option<(String, Option<String>)>> {
+        resolve_url(url, Path::new(page), base_path).map(|resolved| {
+            resolved.map(|url| (url.path.to_string_lossy().into_owned(), url.fragment))
+        })
+    }
+
+    fn local(path: &str, fragment: Option<&str>) -> Option<Option<(String, Option<String>)>> {
+        Some(Some((path.to_string(), fragment.map(str::to_string))))
+    }
+
+    #[test]
+    fn reads_quoted_and_unquoted_attributes() {
+        assert_eq!(
+            tags(r#"<a href="x.html" class='c d' data-x=y hidden>text</a>"#),
+            [tag(
+                "a",
+                &[
+                    ("href", "x.html"),
+                    ("class", "c d"),
+                    ("data-x", "y"),
+                    ("hidden", "")
+                ]
+            )]
+        );
+        assert_eq!(
+            tags(r#"<IMG SRC = "a.png"/><br/>"#),
+            [tag("img", &[("src", "a.png")]), tag("br", &[])]
+        );
+    }
+
+    #[test]
+    fn decodes_entities_in_attributes() {
+        assert_eq!(
+            tags(r#"<a href="a.html?x=1&amp;y=2" title="&lt;3 &#x2F; &#47;">"#),
+            [tag("a", &[("href", "a.html?x=1&y=2"), ("title", "<3 / /")])]
+        );
+    }
+
+    #[test]
+    fn skips_comments_scripts_and_styles() {
+        assert_eq!(
+            tags(
+                "<!-- <a href=\"no.html\"> --><script>if (a<b) {}</script>\
+                 <style>a<b {}</style><p id=x>1 < 2</p>"
+            ),
+            [
+                tag("script", &[]),
+                tag("style", &[]),
+                tag("p", &[("id", "x")])
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_off_site_urls_alone() {
+        assert_eq!(resolve("https://example.com/a", "index.html", "/"), None);
+        assert_eq!(resolve("mailto:a@example.com", "index.html", "/"), None);
+        assert_eq!(resolve("//cdn.example.com/a.js", "index.html", "/"), None);
+    }
+
+    #[test]
+    fn resolves_urls_from_the_page() {
+        assert_eq!(
+            resolve("about.html", "blog/post.html", "/"),
+            local("blog/about.html", None)
+        );
+        assert_eq!(
+            resolve("../index.html?page=2#top", "blog/post.html", "/"),
+            local("index.html", Some("top"))
+        );
+        assert_eq!(
+            resolve("#see%20also", "blog/post.html", "/"),
+            local("blog/post.html", Some("see also"))
+        );
+        assert_eq!(
+            resolve("a%20b.html", "blog/post.html", "/"),
+            local("blog/a b.html", None)
+        );
+        assert_eq!(
+            resolve("notes/x:y.html", "index.html", "/"),
+            local("notes/x:y.html", None)
+        );
+    }
+
+    #[test]
+    fn resolves_directory_urls_to_their_index() {
+        assert_eq!(
+            resolve("docs/", "blog/post.html", "/"),
+            local("blog/docs/index.html", None)
+        );
+        assert_eq!(
+            resolve("./", "blog/post.html", "/"),
+            local("blog/index.html", None)
+        );
+        assert_eq!(
+            resolve("/", "blog/post.html", "/"),
+            local("index.html", None)
+        );
+        assert_eq!(
+            resolve("..", "blog/post.html", "/"),
+            local("index.html", None)
+        );
+    }
+
+    #[test]
+    fn resolves_root_urls_under_the_base_path() {
+        assert_eq!(
+            resolve("/blog/post.html", "index.html", "/"),
+            local("blog/post.html", None)
+        );
+        assert_eq!(
+            resolve("/sub/blog/post.html", "index.html", "/sub/"),
+            local("blog/post.html", None)
+        );
+        assert_eq!(
+            resolve("/sub", "blog/post.html", "/sub/"),
+            local("index.html", None)
+        );
+        assert_eq!(
+            resolve("/blog/post.html", "index.html", "/sub/"),
+            Some(None)
+        );
+    }
+
+    #[test]
+    fn rejects_urls_above_the_root() {
+        assert_eq!(resolve("../../a.html", "blog/post.html", "/"), Some(None));
+        assert_eq!(resolve("/../a.html", "index.html", "/"), Some(None));
+    }
+
+    #[test]
+    fn finds_broken_links_and_orphaned_pages() {
+        let site = tempfile::tempdir().unwrap();
+        let write = |path: &str, html: &str| {
+            let path = site.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, html).unwrap();
+        };
+        write(
+            "index.html",
+            r#"<a href="a.html">A</a><a href="docs/">Docs</a><a href="a.html#nope">A</a>
+               <a href="gone.html">Gone</a><img src="logo.png">"#,
+        );
+        write(
+            "a.html",
+            r##"<h2 id="x">X</h2><h2 id="x">X</h2><a href="#x">X</a>"##,
+        );
+        write("docs/index.html", r#"<a href="../index.html">Home</a>"#);
+        write("orphan.html", r#"<a href="index.html">Home</a>"#);
+        write(
+            "self.html",
+            r##"<a href="self.html">Me</a><a href="#">Top</a>"##,
+        );
+
+        let problems: Vec<(String, String)> = check_site(site.path(), site.path(), "/")
+            .unwrap()
+            .into_iter()
+            .map(|problem| (problem.page.to_string_lossy().into_owned(), problem.message))
+            .collect();
+        let problem = |page: &str, message: &str| (page.to_string(), message.to_string());
+        assert_eq!(
+            problems,
+            [
+                problem("a.html", "duplicate heading ID `x`"),
+                problem(
+                    "index.html",
+                    "broken link `a.html#nope`: there's no `#nope` in `a.html`"
+                ),
+                problem(
+                    "index.html",
+                    "broken link `gone.html`: there's no `gone.html`"
+                ),
+                problem(
+                    "index.html",
+                    "missing asset `logo.png`: there's no `logo.png`"
+                ),
+                problem("orphan.html", "orphaned page: no other page links to it"),
+                problem("self.html", "orphaned page: no other page links to it"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tera::Tera;
+
+/// The page being rendered. Exposed to templates as `current_page`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct CurrentPage {
+    /// The page's Markdown file, relative to `pages/`.
+    pub(crate) path: PathBuf,
+    /// The page's URL, relative to the site root, like `guides/intro.html`,
+    /// or `guides/intro/` with pretty URLs.
+    pub(crate) url: String,
+    /// Where the page is written, relative to the site root, like
+    /// `guides/intro/index.html`.
+    pub(crate) target_path: PathBuf,
+    pub(crate) title: String,
+    /// The directory of the page's section, relative to `pages/`.
+    pub(crate) section: PathBuf,
+}
+
+/// Turns a path from a template into one relative to `pages/` or the site
+/// root, so `pages/guides/intro.md`, `/guides/intro.html` and
+/// `guides/intro.html` can all be compared.
+pub(crate) fn template_path(
+    args: &HashMap<String, Value>,
+    function: &str,
+) -> tera::Result<PathBuf> {
+    let path = args
+        .get("path")
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| tera::Error::msg(format!("`{function}` needs a `path` argument")))?;
+    let path = path.trim_start_matches('/');
+    let path = Path::new(path)
+        .strip_prefix("pages")
+        .unwrap_or(Path::new(path));
+    Ok(path.to_path_buf())
+}
+
+/// Registers the template functions about `current_page`, the page being
+/// rendered, which `current_page` returns. Tera's functions can't see the
+/// template's context, so they're registered once and ask `current_page` for
+/// the page instead.
+///
+/// - `is_active(path=...)` is true if `path` is the current page's Markdown
+///   file, HTML file or URL, like `page.markdown_path` or `page.target_path`.
+/// - `is_ancestor(path=...)` is true if `path` is the directory of a section
+///   containing the current page, like `section.path`.
+pub(crate) fn register_functions(
+    tera: &mut Tera,
+    current_page: impl Fn() -> Option<CurrentPage> + Clone + Send + Sync + 'static,
+) {
+    fn page(
+        current_page: impl Fn() -> Option<CurrentPage>,
+        function: &str,
+    ) -> tera::Result<CurrentPage> {
+        current_page().ok_or_else(|| {
+            tera::Error::msg(format!(
+                "`{function}` can only be used while rendering a page"
+            ))
+        })
+    }
+
+    let current = current_page.clone();
+    tera.register_function("is_active", move |args: &HashMap<String, Value>| {
+        let path = template_path(args, "is_active")?;
+        let current = page(&current, "is_active")?;
+        Ok(Value::Bool(
+            path == current.path || path == current.target_path || path == Path::new(&current.url),
+        ))
+    });
+
+    tera.register_function("is_ancestor", move |args: &HashMap<String, Value>| {
+        let path = template_path(args, "is_ancestor")?;
+        let current = page(&current_page, "is_ancestor")?;
+        Ok(Value::Bool(current.section.starts_with(path)))
+    });
+}
@@ -0,0 +1,148 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+use yaml_rust::Yaml;
+
+/// Converts a YAML node from `PUSHPIN.yaml` to JSON, for templates.
+pub(crate) fn yaml_to_json(yaml: &Yaml) -> Value {
+    match yaml {
+        Yaml::Real(_) => yaml.as_f64().map_or(Value::Null, Value::from),
+        Yaml::Integer(n) => Value::from(*n),
+        Yaml::String(s) => Value::from(s.as_str()),
+        Yaml::Boolean(b) => Value::from(*b),
+        Yaml::Array(items) => Value::Array(items.iter().map(yaml_to_json).collect()),
+        Yaml::Hash(entries) => Value::Object(
+            entries
+                .iter()
+                .filter_map(|(key, value)| {
+                    let key = match key {
+                        Yaml::String(s) => s.clone(),
+                        Yaml::Integer(n) => n.to_string(),
+                        Yaml::Boolean(b) => b.to_string(),
+                        _ => return None,
+                    };
+                    Some((key, yaml_to_json(value)))
+                })
+                .collect(),
+        ),
+        Yaml::Alias(_) | Yaml::Null | Yaml::BadValue => Value::Null,
+    }
+}
+
+/// Converts a TOML value to JSON, for templates. Dates and times become
+/// strings, like they are in YAML.
+pub(crate) fn toml_to_json(toml: toml::Value) -> Value {
+    match toml {
+        toml::Value::String(s) => Value::from(s),
+        toml::Value::Integer(n) => Value::from(n),
+        toml::Value::Float(n) => Value::from(n),
+        toml::Value::Boolean(b) => Value::from(b),
+        toml::Value::Datetime(datetime) => Value::from(datetime.to_string()),
+        toml::Value::Array(items) => Value::Array(items.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// A date's year, month and day, and the time after it, if any, in an order
+/// that sorts oldest first.
+pub(crate) type DateKey<'d> = (u32, u32, u32, &'d str);
+
+/// Parses an ISO 8601 date, like `2024-05-05`, optionally followed by a time,
+/// like `2024-05-05T10:30:00`, so dates can be sorted. Other formats, like
+/// `05-05-2024`, don't sort by date, so they're `None`.
+pub(crate) fn parse_date(date: &str) -> Option<DateKey<'_>> {
+    let number = |part: &str, len: usize| {
+        (part.len() == len && part.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| part.parse::<u32>().ok())
+            .flatten()
+    };
+    let (day, time) = (date.get(..10)?, &date[10..]);
+    let mut parts = day.split('-');
+    let year = number(parts.next()?, 4)?;
+    let month = number(parts.next()?, 2).filter(|month| (1..=12).contains(month))?;
+    let day = number(parts.next()?, 2).filter(|day| (1..=31).contains(day))?;
+    if !(time.is_empty() || time.starts_with(['T', ' '])) {
+        return None;
+    }
+    Some((year, month, day, time.trim_start_matches(['T', ' '])))
+}
+
+/// Reads a CSV file with a header row into a list of objects keyed by the
+/// header. Every value is a string.
+fn parse_csv(contents: &str) -> Result<Value> {
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+    let headers = reader.headers()?.clone();
+    let mut rows = vec![];
+    for record in reader.records() {
+        let record = record?;
+        let row: Map<String, Value> = headers
+            .iter()
+            .zip(record.iter())
+            .map(|(header, value)| (header.to_string(), Value::from(value)))
+            .collect();
+        rows.push(Value::Object(row));
+    }
+    Ok(Value::Array(rows))
+}
+
+/// Parses a data file by its extension, or returns `None` if it isn't a kind
+/// of data file.
+pub(crate) fn parse_data_file(path: &Path) -> Result<Option<Value>> {
+    let extension = path.extension().and_then(|s| s.to_str()).unwrap_or("");
+    if !matches!(extension, "yaml" | "yml" | "json" | "toml" | "csv") {
+        return Ok(None);
+    }
+
+    let contents = fs::read_to_string(path)?;
+    let value = match extension {
+        "yaml" | "yml" => serde_yaml::from_str(&contents)?,
+        "json" => serde_json::from_str(&contents)?,
+        "toml" => toml_to_json(toml::Value::Table(toml::from_str(&contents)?)),
+        _ => parse_csv(&contents)?,
+    };
+    Ok(Some(value))
+}
+
+/// Loads every YAML, JSON, TOML and CSV file under `dir`, keyed by file name
+/// without its extension. Subdirectories become nested objects, so
+/// `data/team/authors.yaml` is `data.team.authors` in templates.
+pub(crate) fn load_data_dir(dir: &Path) -> Result<Value> {
+    let mut data = Map::new();
+    if !dir.is_dir() {
+        return Ok(Value::Object(data));
+    }
+
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        let Some(key) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        let value = if path.is_dir() {
+            load_data_dir(&path)?
+        } else {
+            match parse_data_file(&path)
+                .map_err(|e| anyhow!("Failed to load data file '{}': {e}", path.display()))?
+            {
+                Some(value) => value,
+                None => continue,
+            }
+        };
+        if data.insert(key.to_string(), value).is_some() {
+            return Err(anyhow!(
+                "More than one data file in '{}' is named '{key}'",
+                dir.display()
+            ));
+        }
+    }
+    Ok(Value::Object(data))
+}
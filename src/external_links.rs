@@ -0,0 +1,279 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::check::Problem;
+use crate::LinkCheckConfig;
+
+/// The HTTP status a URL responded with, and when, in seconds since the Unix epoch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedStatus {
+    status: u16,
+    checked_at: u64,
+}
+
+/// The result of checking one URL: its HTTP status, or why there wasn't one.
+type LinkStatus = std::result::Result<u16, String>;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Reads the cache, treating a missing or unreadable file as empty.
+fn load_cache(path: &str) -> BTreeMap<String, CachedStatus> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Returns the host of `url`, like `example.com:8080` for
+/// `https://example.com:8080/path`.
+fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    &rest[..end]
+}
+
+/// Requests `url`, first with `HEAD`, then with `GET` if that fails, since
+/// some servers don't support `HEAD`. Redirects are followed.
+fn fetch(agent: &ureq::Agent, url: &str) -> LinkStatus {
+    let status = |response: std::result::Result<ureq::Response, ureq::Error>| match response {
+        Ok(response) => Ok(response.status()),
+        Err(ureq::Error::Status(status, _)) => Ok(status),
+        Err(ureq::Error::Transport(err)) => Err(match err.message() {
+            Some(message) => format!("{}: {message}", err.kind()),
+            None => err.kind().to_string(),
+        }),
+    };
+    match status(agent.head(url).call()) {
+        Ok(head_status) if head_status < 400 => Ok(head_status),
+        _ => status(agent.get(url).call()),
+    }
+}
+
+/// Checks `urls`, up to `concurrency` at a time, waiting at least
+/// `host_delay_ms` between requests to the same host.
+fn fetch_all(urls: Vec<String>, config: &LinkCheckConfig) -> HashMap<String, LinkStatus> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .user_agent(concat!("pushpin/", env!("CARGO_PKG_VERSION")))
+        .build();
+    let host_delay = Duration::from_millis(config.host_delay_ms);
+
+    let queue = Mutex::new(VecDeque::from(urls));
+    // When each host can next be requested.
+    let next_request: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+    let results = Mutex::new(HashMap::new());
+
+    thread::scope(|scope| {
+        for _ in 0..config.concurrency {
+            scope.spawn(|| loop {
+                let Some(url) = queue.lock().unwrap().pop_front() else {
+                    break;
+                };
+
+                // Reserve the host's next slot, then wait for it.
+                let start = {
+                    let mut next_request = next_request.lock().unwrap();
+                    let now = Instant::now();
+                    let slot = next_request.entry(host(&url).to_string()).or_insert(now);
+                    let start = (*slot).max(now);
+                    *slot = start + host_delay;
+                    start
+                };
+                thread::sleep(start.saturating_duration_since(Instant::now()));
+
+                let status = fetch(&agent, &url);
+                results.lock().unwrap().insert(url, status);
+            });
+        }
+    });
+    results.into_inner().unwrap()
+}
+
+/// Checks every external URL in `urls`, which maps each URL to the pages it's
+/// on, and reports the broken ones on each of their pages.
+///
+/// Working links are cached in `cache_path` for `cache_ttl_hours`, so
+/// repeated runs don't request the same URLs again. Broken links and network
+/// errors aren't cached, so a link that failed once, like with a 503 while its
+/// site was down, is checked again on the next run.
+pub(crate) fn check_external_links(
+    urls: BTreeMap<String, BTreeSet<PathBuf>>,
+    config: &LinkCheckConfig,
+) -> Result<Vec<Problem>> {
+    let mut cache = load_cache(&config.cache_path);
+    let now = now();
+    let ttl = config.cache_ttl_hours * 60 * 60;
+
+    let mut statuses: HashMap<String, LinkStatus> = HashMap::new();
+    let mut uncached = vec![];
+    for url in urls.keys() {
+        match cache.get(url) {
+            Some(cached) if cached.status < 400 && now.saturating_sub(cached.checked_at) < ttl => {
+                statuses.insert(url.clone(), Ok(cached.status));
+            }
+            _ => uncached.push(url.clone()),
+        }
+    }
+    println!(
+        "🔍 checking {} external links ({} cached)",
+        urls.len(),
+        urls.len() - uncached.len()
+    );
+
+    for (url, status) in fetch_all(uncached, config) {
+        if let Ok(status @ ..400) = status {
+            cache.insert(
+                url.clone(),
+                CachedStatus {
+                    status,
+                    checked_at: now,
+                },
+            );
+        }
+        statuses.insert(url, status);
+    }
+    // Forget links that have expired or broken, so the cache doesn't grow forever.
+    cache.retain(|_, cached| cached.status < 400 && now.saturating_sub(cached.checked_at) < ttl);
+    fs::write(&config.cache_path, serde_json::to_string_pretty(&cache)?)?;
+
+    let mut problems = vec![];
+    for (url, pages) in urls {
+        let message = match &statuses[&url] {
+            Ok(status) if *status < 400 => continue,
+            Ok(status) => format!("broken external link `{url}`: HTTP {status}"),
+            Err(err) => format!("broken external link `{url}`: {err}"),
+        };
+        for page in pages {
+            problems.push(Problem {
+                page,
+                message: message.clone(),
+            });
+        }
+    }
+    problems.sort_by(|a, b| a.page.cmp(&b.page));
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Starts a stand-in server on a free port. `/ok` works, `/gone` is a 404,
+    /// and `/flaky` is a 503 for its first two requests, then works. Returns
+    /// the server's URL and the number of requests to each path.
+    fn stand_in_server() -> (String, Arc<Mutex<HashMap<String, usize>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(HashMap::new()));
+        let counts = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                // Skip the headers, up to the blank line.
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+
+                let path = request_line
+                    .split_whitespace()
+                    .nth(1)
+                    .unwrap_or("")
+                    .to_string();
+                let count = {
+                    let mut counts = counts.lock().unwrap();
+                    let count = counts.entry(path.clone()).or_insert(0);
+                    *count += 1;
+                    *count
+                };
+                let status = match path.as_str() {
+                    "/ok" => "200 OK",
+                    "/flaky" if count <= 2 => "503 Service Unavailable",
+                    "/flaky" => "200 OK",
+                    _ => "404 Not Found",
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn checks_links_and_caches_working_ones() {
+        let (server, requests) = stand_in_server();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = LinkCheckConfig {
+            concurrency: 2,
+            host_delay_ms: 0,
+            timeout_secs: 5,
+            cache_path: cache_dir
+                .path()
+                .join("cache.json")
+                .to_string_lossy()
+                .to_string(),
+            cache_ttl_hours: 24,
+        };
+        let urls: BTreeMap<String, BTreeSet<PathBuf>> = ["/ok", "/gone", "/flaky"]
+            .into_iter()
+            .map(|path| {
+                (
+                    format!("{server}{path}"),
+                    BTreeSet::from([PathBuf::from("index.html")]),
+                )
+            })
+            .collect();
+        let check = || -> Vec<String> {
+            check_external_links(urls.clone(), &config)
+                .unwrap()
+                .into_iter()
+                .map(|problem| format!("{}: {}", problem.page.display(), problem.message))
+                .collect()
+        };
+
+        assert_eq!(
+            check(),
+            [
+                format!("index.html: broken external link `{server}/flaky`: HTTP 503"),
+                format!("index.html: broken external link `{server}/gone`: HTTP 404"),
+            ]
+        );
+        // The working link comes from the cache, and the broken ones are
+        // checked again, so the one that recovered isn't reported.
+        assert_eq!(
+            check(),
+            [format!(
+                "index.html: broken external link `{server}/gone`: HTTP 404"
+            )]
+        );
+
+        let requests = requests.lock().unwrap();
+        // `HEAD` only, on the first run.
+        assert_eq!(requests["/ok"], 1);
+        // `HEAD`, then `GET` after it fails, then `HEAD` on the second run.
+        assert_eq!(requests["/flaky"], 3);
+        assert_eq!(requests["/gone"], 4);
+    }
+}
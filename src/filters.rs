@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+use tera::{Filter, Tera};
+
+use crate::html::decode_entities;
+use crate::toc;
+
+/// How many words a reader gets through in a minute, for `reading_time`.
+const DEFAULT_WORDS_PER_MINUTE: u64 = 200;
+
+/// Elements with no closing tag, which `truncate_html` mustn't close.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Renders Markdown to HTML the same way pages are rendered.
+/// With `true`, a single paragraph isn't wrapped in `<p>`.
+type MarkdownRenderer = dyn Fn(&str, bool) -> anyhow::Result<String> + Send + Sync;
+
+/// A piece of an HTML document: a tag, or the text between tags.
+enum HtmlPiece<'a> {
+    Tag(&'a str),
+    Text(&'a str),
+}
+
+/// Splits `html` into tags and text. Comments are dropped, and like in
+/// `check::start_tags`, this is only enough for the markup Pushpin writes.
+/// The contents of scripts and styles are one piece of text, even if they
+/// contain a `<`.
+fn html_pieces(html: &str) -> Vec<HtmlPiece<'_>> {
+    let mut pieces = vec![];
+    let mut rest = html;
+    while !rest.is_empty() {
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+        } else if rest.starts_with('<') {
+            let end = rest.find('>').map_or(rest.len(), |end| end + 1);
+            let tag = &rest[..end];
+            pieces.push(HtmlPiece::Tag(tag));
+            rest = &rest[end..];
+
+            let (name, closing) = tag_name(tag);
+            if !closing && (name == "script" || name == "style") {
+                let end = rest
+                    .to_ascii_lowercase()
+                    .find(&format!("</{name}"))
+                    .unwrap_or(rest.len());
+                if end > 0 {
+                    pieces.push(HtmlPiece::Text(&rest[..end]));
+                }
+                rest = &rest[end..];
+            }
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            pieces.push(HtmlPiece::Text(&rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+    pieces
+}
+
+/// Returns the lowercase name of a tag, like `p` for `<p class="x">` or
+/// `</p>`, and whether it's a closing tag.
+fn tag_name(tag: &str) -> (String, bool) {
+    let inner = tag.trim_start_matches('<');
+    let (inner, closing) = match inner.strip_prefix('/') {
+        Some(inner) => (inner, true),
+        None => (inner, false),
+    };
+    let end = inner
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .unwrap_or(inner.len());
+    (inner[..end].to_ascii_lowercase(), closing)
+}
+
+/// Removes the tags from `html`, leaving its text. Scripts and styles are
+/// removed entirely.
+fn strip_html(html: &str) -> String {
+    let mut text = String::new();
+    let mut skipping: Option<String> = None;
+    for piece in html_pieces(html) {
+        match piece {
+            HtmlPiece::Tag(tag) => {
+                let (name, closing) = tag_name(tag);
+                match &skipping {
+                    Some(skipped) if closing && *skipped == name => skipping = None,
+                    Some(_) => {}
+                    None if !closing && (name == "script" || name == "style") => {
+                        skipping = Some(name)
+                    }
+                    None => {}
+                }
+            }
+            HtmlPiece::Text(piece) if skipping.is_none() => text.push_str(piece),
+            HtmlPiece::Text(_) => {}
+        }
+    }
+    decode_entities(&text)
+}
+
+fn word_count(text: &str) -> u64 {
+    strip_html(text).split_whitespace().count() as u64
+}
+
+/// Cuts `html` down to `length` characters of text, then adds `end` and
+/// closes any tags left open. Character references count as one character.
+fn truncate_html(html: &str, length: usize, end: &str) -> String {
+    let mut truncated = String::new();
+    let mut open_tags: Vec<String> = vec![];
+    let mut remaining = length;
+    for piece in html_pieces(html) {
+        match piece {
+            HtmlPiece::Tag(tag) => {
+                let (name, closing) = tag_name(tag);
+                if closing {
+                    if let Some(index) = open_tags.iter().rposition(|open| *open == name) {
+                        open_tags.truncate(index);
+                    }
+                } else if !tag.ends_with("/>") && !VOID_ELEMENTS.contains(&name.as_str()) {
+                    open_tags.push(name);
+                }
+                truncated.push_str(tag);
+            }
+            HtmlPiece::Text(text) => {
+                let mut chars = text.char_indices();
+                while let Some((i, c)) = chars.next() {
+                    if remaining == 0 {
+                        truncated.push_str(end);
+                        for name in open_tags.iter().rev() {
+                            truncated.push_str(&format!("</{name}>"));
+                        }
+                        return truncated;
+                    }
+                    // Keep a character reference, like `&amp;`, whole.
+                    let reference = (c == '&')
+                        .then(|| {
+                            text[i..].find(';').filter(|&end| {
+                                (2..=10).contains(&end)
+                                    && text[i + 1..i + end]
+                                        .chars()
+                                        .all(|c| c.is_ascii_alphanumeric() || c == '#')
+                            })
+                        })
+                        .flatten();
+                    match reference {
+                        Some(reference_end) => {
+                            truncated.push_str(&text[i..=i + reference_end]);
+                            for _ in 0..reference_end {
+                                chars.next();
+                            }
+                        }
+                        None => truncated.push(c),
+                    }
+                    remaining -= 1;
+                }
+            }
+        }
+    }
+    truncated
+}
+
+fn string_value<'a>(value: &'a Value, filter: &str) -> tera::Result<&'a str> {
+    value
+        .as_str()
+        .ok_or_else(|| tera::Error::msg(format!("`{filter}` can only be used on strings")))
+}
+
+fn number_arg(
+    args: &HashMap<String, Value>,
+    name: &str,
+    filter: &str,
+) -> tera::Result<Option<u64>> {
+    match args.get(name) {
+        None => Ok(None),
+        Some(value) => value.as_u64().map(Some).ok_or_else(|| {
+            tera::Error::msg(format!("`{filter}` needs `{name}` to be a whole number"))
+        }),
+    }
+}
+
+struct MarkdownFilter {
+    render: Box<MarkdownRenderer>,
+}
+
+impl Filter for MarkdownFilter {
+    fn filter(&self, value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let markdown = string_value(value, "markdown")?;
+        let inline = match args.get("inline") {
+            None => false,
+            Some(inline) => inline
+                .as_bool()
+                .ok_or_else(|| tera::Error::msg("`markdown` needs `inline` to be a boolean"))?,
+        };
+        (self.render)(markdown, inline)
+            .map(Value::String)
+            .map_err(|e| tera::Error::msg(format!("`markdown` failed to render: {e}")))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+struct TruncateHtmlFilter;
+
+impl Filter for TruncateHtmlFilter {
+    fn filter(&self, value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+        let html = string_value(value, "truncate_html")?;
+        let length = number_arg(args, "length", "truncate_html")?.unwrap_or(255);
+        let end = match args.get("end") {
+            None => "…",
+            Some(end) => end
+                .as_str()
+                .ok_or_else(|| tera::Error::msg("`truncate_html` needs `end` to be a string"))?,
+        };
+        Ok(Value::String(truncate_html(html, length as usize, end)))
+    }
+
+    fn is_safe(&self) -> bool {
+        true
+    }
+}
+
+/// Registers the `markdown` filter, which renders a string of Markdown with
+/// `render_markdown`, so it comes out like the content of the page it's used
+/// on. With `inline=true`, a single paragraph isn't wrapped in `<p>`.
+pub(crate) fn register_markdown_filter(
+    tera: &mut Tera,
+    render_markdown: impl Fn(&str, bool) -> anyhow::Result<String> + Send + Sync + 'static,
+) {
+    tera.register_filter(
+        "markdown",
+        MarkdownFilter {
+            render: Box::new(render_markdown),
+        },
+    );
+}
+
+/// Registers Pushpin's own template filters, other than `markdown`, which
+/// depends on the page being rendered. See `register_markdown_filter`.
+///
+/// - `slugify` turns text into a slug, the same way heading IDs are made.
+/// - `word_count` and `reading_time` count the words in text or HTML, and how
+///   many minutes they take to read, at `words_per_minute` (200 by default).
+/// - `strip_html` removes the tags from HTML, leaving its text.
+/// - `truncate_html` cuts HTML to `length` characters of text, keeping its
+///   tags balanced, and adds `end` (`…` by default).
+pub(crate) fn register_filters(tera: &mut Tera) {
+    tera.register_filter("truncate_html", TruncateHtmlFilter);
+
+    tera.register_filter("slugify", |value: &Value, _: &HashMap<String, Value>| {
+        Ok(Value::String(toc::slugify(string_value(value, "slugify")?)))
+    });
+    tera.register_filter("strip_html", |value: &Value, _: &HashMap<String, Value>| {
+        Ok(Value::String(strip_html(string_value(
+            value,
+            "strip_html",
+        )?)))
+    });
+    tera.register_filter("word_count", |value: &Value, _: &HashMap<String, Value>| {
+        Ok(Value::from(word_count(string_value(value, "word_count")?)))
+    });
+    tera.register_filter(
+        "reading_time",
+        |value: &Value, args: &HashMap<String, Value>| {
+            let words = word_count(string_value(value, "reading_time")?);
+            let words_per_minute = number_arg(args, "words_per_minute", "reading_time")?
+                .unwrap_or(DEFAULT_WORDS_PER_MINUTE)
+                .max(1);
+            // Anything to read takes at least a minute.
+            Ok(Value::from(words.div_ceil(words_per_minute)))
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_tags() {
+        assert_eq!(
+            strip_html("<p>One <em>two</em></p>\n<p>three</p>"),
+            "One two\nthree"
+        );
+        assert_eq!(strip_html("a<br>b<br/>c<img src=\"x.png\" />d"), "abcd");
+        assert_eq!(strip_html("a<!-- <p>b</p> -->c"), "ac");
+        assert_eq!(
+            strip_html("<p>Fish &amp; Chips &#x2F; &lt;3</p>"),
+            "Fish & Chips / <3"
+        );
+    }
+
+    #[test]
+    fn strips_scripts_and_styles() {
+        assert_eq!(
+            strip_html("a<script>if (x < y) { z(); }</script>b<style>p > em {}</style>c"),
+            "abc"
+        );
+        assert_eq!(strip_html("a<SCRIPT>x</SCRIPT>b"), "ab");
+    }
+
+    #[test]
+    fn leaves_short_html_alone() {
+        assert_eq!(truncate_html("<p>Hello</p>", 5, "…"), "<p>Hello</p>");
+        assert_eq!(truncate_html("<p>Hi</p>", 10, "…"), "<p>Hi</p>");
+    }
+
+    #[test]
+    fn closes_nested_tags() {
+        let html = "<p>One <em>two <strong>three</strong></em> four</p><p>five</p>";
+        assert_eq!(
+            truncate_html(html, 9, "…"),
+            "<p>One <em>two <strong>t…</strong></em></p>"
+        );
+        assert_eq!(
+            truncate_html(html, 13, "…"),
+            "<p>One <em>two <strong>three</strong></em>…</p>"
+        );
+        assert_eq!(
+            truncate_html(html, 18, " [more]"),
+            "<p>One <em>two <strong>three</strong></em> four</p><p> [more]</p>"
+        );
+    }
+
+    #[test]
+    fn keeps_character_references_whole() {
+        let html = "<p>A &amp; B &#x2F; C</p>";
+        assert_eq!(truncate_html(html, 2, "…"), "<p>A …</p>");
+        assert_eq!(truncate_html(html, 3, "…"), "<p>A &amp;…</p>");
+        assert_eq!(truncate_html(html, 7, "…"), "<p>A &amp; B &#x2F;…</p>");
+        // A `&` that doesn't start a reference is a character of its own.
+        assert_eq!(truncate_html("a & b; c", 3, "…"), "a &…");
+        assert_eq!(truncate_html("a &; b", 3, "…"), "a &…");
+    }
+
+    #[test]
+    fn doesnt_close_void_or_self_closing_tags() {
+        assert_eq!(
+            truncate_html("<p>A<br>B<img src=\"x.png\">C<x-icon/>D E</p>", 4, "…"),
+            "<p>A<br>B<img src=\"x.png\">C<x-icon/>D…</p>"
+        );
+    }
+}
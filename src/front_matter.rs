@@ -0,0 +1,253 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use crate::data;
+use crate::source_map;
+
+/// The languages front matter can be written in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    /// Between `---` lines.
+    Yaml,
+    /// Between `+++` lines, like in Hugo.
+    Toml,
+    /// A JSON object at the very start of the page.
+    Json,
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Yaml => "YAML",
+            Format::Toml => "TOML",
+            Format::Json => "JSON",
+        }
+    }
+}
+
+/// A page's unparsed front matter.
+#[derive(Debug)]
+pub(crate) struct FrontMatter<'a> {
+    format: Format,
+    text: &'a str,
+    /// The line of the page that `text` starts on, counting from 1.
+    line: usize,
+}
+
+/// Splits a page into its front matter, if it has any, and its Markdown body.
+/// The front matter is YAML between `---` fences, TOML between `+++` fences,
+/// or a JSON object starting the page.
+///
+/// Fences only count on lines of their own, with the opening fence on the
+/// first line, so a `---` in the body or in a YAML string doesn't end the
+/// front matter. A byte order mark and `\r\n` line endings are fine, but
+/// blank lines before the front matter are an error.
+pub(crate) fn split_document(content: &str) -> Result<(Option<FrontMatter<'_>>, &str)> {
+    let page = content.strip_prefix('\u{feff}').unwrap_or(content);
+
+    // A JSON object starts with a key or is empty, so a page starting with a
+    // shortcode, like `{{< youtube >}}`, isn't mistaken for one.
+    if page
+        .strip_prefix('{')
+        .is_some_and(|rest| rest.trim_start().starts_with(['"', '}']))
+    {
+        // The JSON object ends wherever its closing brace is.
+        let mut objects = serde_json::Deserializer::from_str(page).into_iter::<Value>();
+        let end = match objects.next() {
+            Some(Ok(_)) => objects.byte_offset(),
+            // Keep the whole page as the front matter, so parsing it reports
+            // the error.
+            _ => page.len(),
+        };
+        let front_matter = FrontMatter {
+            format: Format::Json,
+            text: &page[..end],
+            line: 1,
+        };
+        return Ok((Some(front_matter), &page[end..]));
+    }
+
+    if let Some((format, text, body)) = split_fenced(page) {
+        let front_matter = FrontMatter {
+            format,
+            text,
+            line: 2,
+        };
+        return Ok((Some(front_matter), body));
+    }
+
+    // Otherwise front matter after blank lines would quietly become part of
+    // the body.
+    let blank_lines: Vec<_> = page
+        .split_inclusive('\n')
+        .take_while(|line| line.trim().is_empty())
+        .collect();
+    let blank_len = blank_lines.iter().map(|line| line.len()).sum::<usize>();
+    if !blank_lines.is_empty() && split_fenced(&page[blank_len..]).is_some() {
+        return Err(anyhow!(
+            "front matter has to start on the first line, but it starts on line {}",
+            blank_lines.len() + 1
+        ));
+    }
+    Ok((None, page))
+}
+
+/// Splits `page` into the format and text of its fenced front matter and its
+/// body, if it starts with a `---` or `+++` fence that's closed.
+fn split_fenced(page: &str) -> Option<(Format, &str, &str)> {
+    let is_fence = |line: &str, fence: &str| line.trim_end() == fence;
+    let first_line_end = page.find('\n').map_or(page.len(), |end| end + 1);
+    let format = match &page[..first_line_end] {
+        line if is_fence(line, "---") => Format::Yaml,
+        line if is_fence(line, "+++") => Format::Toml,
+        _ => return None,
+    };
+    let fence = if format == Format::Yaml { "---" } else { "+++" };
+
+    let mut end = first_line_end;
+    for line in page[first_line_end..].split_inclusive('\n') {
+        if is_fence(line, fence) {
+            return Some((
+                format,
+                &page[first_line_end..end],
+                &page[end + line.len()..],
+            ));
+        }
+        end += line.len();
+    }
+    // Without a closing fence, the first line is a thematic break, not a fence.
+    None
+}
+
+impl FrontMatter<'_> {
+    /// Parses the front matter into the same JSON form, whatever its format.
+    /// Errors end with the line and column of the page they're on.
+    pub(crate) fn parse(&self) -> Result<Value> {
+        let message = match self.format {
+            // Blank lines in place of the lines before the front matter make
+            // the YAML's lines the page's lines, so the error's location is
+            // already right. JSON front matter starts the page anyway.
+            Format::Yaml => {
+                let text = "\n".repeat(self.line - 1) + self.text;
+                match serde_yaml::from_str::<Value>(&text) {
+                    Ok(value) => return Ok(value),
+                    Err(err) => err.to_string(),
+                }
+            }
+            Format::Toml => match toml::from_str::<toml::Table>(self.text) {
+                Ok(table) => return Ok(data::toml_to_json(toml::Value::Table(table))),
+                Err(err) => {
+                    let offset = err.span().map_or(0, |span| span.start);
+                    let (line, column) = source_map::position(self.text, offset);
+                    format!(
+                        "{} at line {} column {column}",
+                        err.message().trim().replace('\n', ", "),
+                        self.line + line - 1
+                    )
+                }
+            },
+            Format::Json => match serde_json::from_str::<Value>(self.text) {
+                Ok(value) => return Ok(value),
+                Err(err) => err.to_string(),
+            },
+        };
+        Err(anyhow!("invalid {}: {message}", self.format.name()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the error from parsing the front matter of `page`.
+    fn parse_error(page: &str) -> String {
+        let (front_matter, _) = split_document(page).unwrap();
+        front_matter.unwrap().parse().unwrap_err().to_string()
+    }
+
+    /// Returns the format and text of `page`'s front matter, if it has any,
+    /// and its body.
+    fn split(page: &str) -> (Option<(Format, &str)>, &str) {
+        let (front_matter, body) = split_document(page).unwrap();
+        (front_matter.map(|f| (f.format, f.text)), body)
+    }
+
+    #[test]
+    fn splits_front_matter_from_the_body() {
+        assert_eq!(
+            split("---\ntitle: A\n---\nText\n\n---\n\nMore\n"),
+            (Some((Format::Yaml, "title: A\n")), "Text\n\n---\n\nMore\n")
+        );
+        assert_eq!(
+            split("---\ntitle: \"A --- B\"\nabout: |\n  ---\n---\nText\n"),
+            (
+                Some((Format::Yaml, "title: \"A --- B\"\nabout: |\n  ---\n")),
+                "Text\n"
+            )
+        );
+        assert_eq!(
+            split("+++\ntitle = \"A\"\n+++\nText\n"),
+            (Some((Format::Toml, "title = \"A\"\n")), "Text\n")
+        );
+        assert_eq!(
+            split("{\"title\": \"A\"}\nText\n"),
+            (Some((Format::Json, "{\"title\": \"A\"}")), "\nText\n")
+        );
+    }
+
+    #[test]
+    fn leaves_pages_without_front_matter_alone() {
+        assert_eq!(
+            split("---\n\nA page that starts with a rule.\n"),
+            (None, "---\n\nA page that starts with a rule.\n")
+        );
+        assert_eq!(
+            split("{{< youtube id=\"x\" >}}\n"),
+            (None, "{{< youtube id=\"x\" >}}\n")
+        );
+    }
+
+    #[test]
+    fn accepts_a_byte_order_mark_and_windows_line_endings() {
+        assert_eq!(
+            split("\u{feff}---\r\ntitle: A\r\n---\r\nText\r\n"),
+            (Some((Format::Yaml, "title: A\r\n")), "Text\r\n")
+        );
+        let (front_matter, _) = split_document("\u{feff}---\r\ntitle: A\r\n---\r\n").unwrap();
+        assert_eq!(
+            front_matter.unwrap().parse().unwrap(),
+            serde_json::json!({"title": "A"})
+        );
+    }
+
+    #[test]
+    fn rejects_front_matter_after_blank_lines() {
+        assert_eq!(
+            split_document("\n\n---\ntitle: A\n---\nText\n")
+                .unwrap_err()
+                .to_string(),
+            "front matter has to start on the first line, but it starts on line 3"
+        );
+    }
+
+    #[test]
+    fn reports_errors_at_the_line_of_the_page() {
+        assert_eq!(
+            parse_error("---\ntitle: A\ndate: [2024\n---\n"),
+            "invalid YAML: did not find expected ',' or ']' at line 4 column 1, \
+             while parsing a flow sequence at line 3 column 7"
+        );
+        assert_eq!(
+            parse_error("+++\ntitle = \"A\"\ndate = \n+++\n"),
+            "invalid TOML: invalid string, expected `\"`, `'` at line 3 column 8"
+        );
+        assert_eq!(
+            parse_error("{\n  \"title\": \"A\",\n  \"date\": 2024-05\n}\n"),
+            "invalid JSON: expected `,` or `}` at line 3 column 15"
+        );
+        assert_eq!(
+            parse_error("\u{feff}---\r\ntitle: A\r\nsub: b: c\r\n---\r\n"),
+            "invalid YAML: mapping values are not allowed in this context at line 3 column 7"
+        );
+    }
+}
@@ -1,13 +1,41 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
-use std::io::Read;
 use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{Arc, Weak};
 
+use crate::admonitions;
+use crate::current_page;
+use crate::current_page::CurrentPage;
+use crate::data;
+use crate::filters;
+use crate::front_matter::split_document;
+use crate::front_matter::FrontMatter;
+use crate::highlight;
+use crate::links;
+use crate::links::LinkBase;
+use crate::links::LinkError;
+use crate::links::LinkTargets;
+use crate::math;
+use crate::math::MathError;
+use crate::paginator;
+use crate::shortcodes;
+use crate::shortcodes::Shortcode;
+use crate::shortcodes::ShortcodeError;
+use crate::site_functions;
+use crate::source_map;
+use crate::source_map::Source;
+use crate::toc;
+use crate::toc::TocEntry;
 use crate::Config;
+use crate::MarkdownConfig;
+use crate::Post;
 use anyhow::{anyhow, Result};
-use pulldown_cmark::Alignment;
-use pulldown_cmark::DefaultBrokenLinkCallback;
 use pulldown_cmark::Event;
 use pulldown_cmark::Options;
 use pulldown_cmark::Parser;
@@ -15,155 +43,637 @@ use pulldown_cmark::Tag;
 use pulldown_cmark::TagEnd;
 use pulldown_cmark::TextMergeStream;
 use serde::Serialize;
+use serde_json::Map;
 use serde_json::Value; // Added this import
 use tera::Tera;
 
-/// Helper to split a document into optional front matter and main content.
-/// Returns a tuple of (Option<yaml_string>, main_content_string).
-fn split_document(content: &str) -> Result<(Option<&str>, &str)> {
-    let content = content.trim_start();
-    if !content.starts_with("---") {
-        return Ok((None, content));
-    }
+/// The marker that ends a page's summary.
+const SUMMARY_MARKER: &str = "<!-- more -->";
 
-    // Split the document into at most 3 parts based on the `---` delimiter.
-    // 1. An empty string from before the first `---`
-    // 2. The YAML front matter
-    // 3. The rest of the document (the main markdown content)
-    let parts: Vec<&str> = content.splitn(3, "---").collect();
+/// Returns the Markdown making up a page's summary: everything before the
+/// `<!-- more -->` marker, or the first `max_words` words if there is no marker.
+/// `shortcodes` are the spans of the shortcodes in `markdown`; a cut inside
+/// one moves to before it, so the shortcode isn't split in two.
+fn split_summary<'m>(markdown: &'m str, max_words: usize, shortcodes: &[Range<usize>]) -> &'m str {
+    let end = summary_end(markdown, max_words);
+    let end = shortcodes
+        .iter()
+        .find(|span| span.start < end && end < span.end)
+        .map_or(end, |span| span.start);
+    &markdown[..end]
+}
 
-    if parts.len() < 3 {
-        // This means we didn't find two `---` delimiters, so it's not valid front matter.
-        return Ok((None, content));
+/// Returns where the summary in `split_summary` ends, before looking at shortcodes.
+fn summary_end(markdown: &str, max_words: usize) -> usize {
+    if let Some(marker_index) = markdown.find(SUMMARY_MARKER) {
+        return marker_index;
     }
 
-    let front_matter = parts[1];
-    let main_content = parts[2];
+    if max_words == 0 {
+        return 0;
+    }
 
-    Ok((Some(front_matter), main_content))
+    // Cut the source right after the last allowed word, keeping the original
+    // whitespace and Markdown syntax of everything before it.
+    let mut words = 0;
+    let mut in_word = false;
+    for (i, c) in markdown.char_indices() {
+        if c.is_whitespace() {
+            if in_word {
+                words += 1;
+                if words == max_words {
+                    return i;
+                }
+            }
+            in_word = false;
+        } else {
+            in_word = true;
+        }
+    }
+    markdown.len()
 }
 
-fn push_toc(iter: &mut Vec<Event>, config: Config) {
-    iter.push(Event::Start(Tag::Table(vec![Alignment::Left; 2])));
-    for post in config.posts {
-        let date_string = post.date.replace("-", "/");
+/// Parses the `key=value` arguments of a macro such as `[[ListPosts summary=true]]`.
+/// Returns `None` if `text` is not an invocation of the macro `name`.
+fn parse_macro_args<'t>(text: &'t str, name: &str) -> Option<Vec<(&'t str, &'t str)>> {
+    let inner = text.trim().strip_prefix("[[")?.strip_suffix("]]")?;
+    let mut parts = inner.split_whitespace();
+    if parts.next()? != name {
+        return None;
+    }
+    Some(
+        parts
+            .map(|arg| arg.split_once('=').unwrap_or((arg, "true")))
+            .collect(),
+    )
+}
 
-        iter.push(Event::Start(Tag::TableRow));
+/// The template `[[ListPosts]]` renders through. Sites can override it by
+/// adding their own `templates/macros/list_posts.html`.
+const LIST_POSTS_TEMPLATE: &str = "macros/list_posts.html";
 
-        iter.push(Event::Start(Tag::TableCell));
-        iter.push(Event::Html(
-            format!(r#"<div style="color: grey; font-weight: lighter;">{date_string}</div>"#,)
-                .into(),
-        ));
-        iter.push(Event::End(TagEnd::TableCell));
-
-        iter.push(Event::Start(Tag::TableCell));
-        iter.push(Event::Html(
-            format!(
-                r#"<a class="index-link" href="{}">{post_title}</a>"#,
-                post.path.replace("md", "html"),
-                post_title = post.title
-            )
-            .into(),
-        ));
-        iter.push(Event::End(TagEnd::TableCell));
+const DEFAULT_LIST_POSTS_TEMPLATE: &str = r#"<table class="list-posts">
+{%- for post in posts %}
+<tr>
+<td><div class="post-date">{{ post.date | replace(from="-", to="/") }}</div></td>
+<td><a class="index-link" href="{{ post.url }}">{{ post.title }}</a>
+{%- if summary and post.summary %}<div class="post-summary">{{ post.summary | safe }}</div>{% endif %}</td>
+</tr>
+{%- endfor %}
+</table>
+"#;
+
+/// Renders the `[[ListPosts]]` macro. Supported arguments:
+/// - `limit=N`: show at most N posts.
+/// - `tag=T`: only show posts tagged T in `PUSHPIN.yaml`.
+/// - `sort=date|title`: sort the posts; by default they keep their `PUSHPIN.yaml` order.
+/// - `order=asc|desc`: the sort direction.
+/// - `summary=true`: expose each post's summary to the template.
+///
+/// Each post's `url` is relative to `base`.
+fn render_list_posts(
+    args: &[(&str, &str)],
+    config: &Config,
+    tera: &Tera,
+    base: LinkBase,
+) -> Result<String> {
+    let mut posts: Vec<&Post> = config.posts.iter().collect();
+    let mut limit = None;
+    let mut descending = false;
+    let mut show_summaries = false;
+
+    for (key, value) in args {
+        match *key {
+            "limit" => {
+                limit = Some(value.parse::<usize>().map_err(|_| {
+                    anyhow!("Invalid `limit` for [[ListPosts]]: '{value}' is not a number")
+                })?)
+            }
+            "tag" => posts.retain(|post| post.tags.iter().any(|tag| tag == value)),
+            "sort" => match *value {
+                "date" => {
+                    if let Some(post) = posts
+                        .iter()
+                        .find(|post| data::parse_date(&post.date).is_none())
+                    {
+                        return Err(anyhow!(
+                            "Can't sort [[ListPosts]] by date: '{}' isn't a date like `2024-05-05`, in post '{}'",
+                            post.date,
+                            post.title
+                        ));
+                    }
+                    posts.sort_by_key(|post| data::parse_date(&post.date))
+                }
+                "title" => posts.sort_by(|a, b| a.title.cmp(&b.title)),
+                _ => return Err(anyhow!("Invalid `sort` for [[ListPosts]]: '{value}'")),
+            },
+            "order" => match *value {
+                "asc" => descending = false,
+                "desc" => descending = true,
+                _ => return Err(anyhow!("Invalid `order` for [[ListPosts]]: '{value}'")),
+            },
+            "summary" => show_summaries = *value == "true",
+            _ => return Err(anyhow!("Unknown argument '{key}' for [[ListPosts]]")),
+        }
+    }
 
-        iter.push(Event::End(TagEnd::TableRow));
+    if descending {
+        posts.reverse();
     }
-    iter.push(Event::End(TagEnd::Table));
+    if let Some(limit) = limit {
+        posts.truncate(limit);
+    }
+    let posts: Vec<Post> = posts
+        .into_iter()
+        .map(|post| Post {
+            url: base.page_url(&post.target_path, config.pretty_urls),
+            ..post.clone()
+        })
+        .collect();
+
+    let mut context = tera::Context::new();
+    context.insert("posts", &posts);
+    context.insert("summary", &show_summaries);
+    tera.render(LIST_POSTS_TEMPLATE, &context)
+        .map_err(|err| anyhow!("Failed to render [[ListPosts]]: {err:?}"))
 }
 
 fn expand_macros<'a>(
-    iter: TextMergeStream<'a, Parser<'a, DefaultBrokenLinkCallback>>,
+    iter: impl Iterator<Item = Event<'a>>,
     config: &Config,
+    tera: &Tera,
+    base: LinkBase,
 ) -> Result<Vec<Event<'a>>> {
+    let events: Vec<Event> = iter.collect();
     let mut transformed: Vec<Event> = Vec::new();
-    for event in iter {
-        match event {
-            Event::Text(text) if text.as_ref() == "[[ListPosts]]" => {
-                push_toc(&mut transformed, config.clone())
+    let mut i = 0;
+    while i < events.len() {
+        // A macro alone in its paragraph replaces the whole paragraph, so its
+        // output isn't wrapped in a `<p>`.
+        if let (
+            Event::Start(Tag::Paragraph),
+            Some(Event::Text(text)),
+            Some(Event::End(TagEnd::Paragraph)),
+        ) = (&events[i], events.get(i + 1), events.get(i + 2))
+        {
+            if let Some(args) = parse_macro_args(text, "ListPosts") {
+                let html = render_list_posts(&args, config, tera, base)?;
+                transformed.push(Event::Html(html.into()));
+                i += 3;
+                continue;
+            }
+        }
+
+        match &events[i] {
+            Event::Text(text) => match parse_macro_args(text, "ListPosts") {
+                Some(args) => {
+                    let html = render_list_posts(&args, config, tera, base)?;
+                    transformed.push(Event::Html(html.into()));
+                }
+                None => transformed.push(events[i].clone()),
+            },
+            event => transformed.push(event.clone()),
+        }
+        i += 1;
+    }
+    Ok(transformed)
+}
+
+/// Renders a shortcode through its template, `templates/shortcodes/<name>.html`.
+/// The template sees the page's context, the shortcode's arguments, and for
+/// the block form, the rendered body as `body`, with links relative to `base`.
+fn render_shortcode(
+    shortcode: &Shortcode,
+    config: &Config,
+    tera: &Tera,
+    context: &tera::Context,
+    base: LinkBase,
+) -> Result<String> {
+    let template = format!("shortcodes/{}.html", shortcode.name);
+    if !tera.get_template_names().any(|name| name == template) {
+        return Err(anyhow!(
+            "Unknown shortcode '{}': there is no 'templates/{template}'",
+            shortcode.name
+        ));
+    }
+
+    let mut shortcode_context = context.clone();
+    for (key, value) in &shortcode.args {
+        shortcode_context.insert(key, value);
+    }
+    if let Some(body) = &shortcode.body {
+        let (body_html, _) = render_markdown(body, config, tera, context, base)?;
+        shortcode_context.insert("body", &body_html);
+    }
+
+    tera.render(&template, &shortcode_context)
+        .map_err(|err| anyhow!("Failed to render shortcode '{}': {err:?}", shortcode.name))
+}
+
+/// Builds the context Markdown in a page is rendered with: the page's
+/// template context, plus its front matter as `page`, for shortcodes.
+fn markdown_context(global_context: &tera::Context, front_matter: &Value) -> tera::Context {
+    let mut context = global_context.clone();
+    match front_matter {
+        Value::Object(map) => {
+            for (key, value) in map {
+                context.insert(key, value);
             }
-            _ => transformed.push(event.clone()),
+            context.insert("page", map);
         }
+        _ => context.insert("page", &serde_json::Map::new()),
+    }
+    context
+}
+
+/// The page being rendered, for the template functions and filters that
+/// depend on it. See `rendering`.
+struct PageRender {
+    /// The page's configuration, with its own Markdown settings.
+    config: Config,
+    /// The page's Markdown context. See `markdown_context`.
+    context: tera::Context,
+    current_page: Option<CurrentPage>,
+}
+
+thread_local! {
+    /// The page being rendered on this thread. Tera's functions and filters
+    /// can't see the context they're called from, so they read it from here.
+    static RENDERING: RefCell<Option<Rc<PageRender>>> = const { RefCell::new(None) };
+}
+
+/// Sets the page being rendered on this thread, with its `config` and
+/// Markdown `context`, until the returned guard is dropped.
+fn rendering(config: &Config, context: &tera::Context) -> Rendering {
+    let current_page = context
+        .get("current_page")
+        .and_then(|value| serde_json::from_value::<CurrentPage>(value.clone()).ok());
+    let page = Rc::new(PageRender {
+        config: config.clone(),
+        context: context.clone(),
+        current_page,
+    });
+    Rendering(RENDERING.with(|rendering| rendering.replace(Some(page))))
+}
+
+/// Puts back the page that was being rendered before `rendering` when dropped.
+struct Rendering(Option<Rc<PageRender>>);
+
+impl Drop for Rendering {
+    fn drop(&mut self) {
+        RENDERING.with(|rendering| *rendering.borrow_mut() = self.0.take());
+    }
+}
+
+/// Returns the page being rendered on this thread. See `rendering`.
+fn rendered_page() -> Option<Rc<PageRender>> {
+    RENDERING.with(|rendering| rendering.borrow().clone())
+}
+
+/// Finishes `tera` for rendering pages, registering the functions and
+/// filters that depend on the page being rendered: `is_active` and
+/// `is_ancestor`, which use its `current_page`, and the `markdown` filter,
+/// which renders with its Markdown settings and context, through the
+/// finished templates. See `rendering`.
+fn page_engine(mut tera: Tera) -> Arc<Tera> {
+    Arc::new_cyclic(|engine: &Weak<Tera>| {
+        current_page::register_functions(&mut tera, || {
+            rendered_page().and_then(|page| page.current_page.clone())
+        });
+        let engine = engine.clone();
+        filters::register_markdown_filter(&mut tera, move |markdown, inline| {
+            let page = rendered_page()
+                .ok_or_else(|| anyhow!("it can only be used while rendering a page"))?;
+            // Markdown rendered by the filter can use the filter too.
+            let tera = engine
+                .upgrade()
+                .ok_or_else(|| anyhow!("the templates are gone"))?;
+            if inline {
+                process_inline_markdown(markdown, &page.config, &tera, &page.context)
+            } else {
+                process_markdown_content(markdown, &page.config, &tera, &page.context)
+            }
+        });
+        tera
+    })
+}
+
+/// Returns the pulldown-cmark extensions turned on in `markdown`.
+fn markdown_options(markdown: &MarkdownConfig) -> Options {
+    let mut options = Options::empty();
+    options.set(Options::ENABLE_TABLES, markdown.tables);
+    options.set(Options::ENABLE_FOOTNOTES, markdown.footnotes);
+    options.set(Options::ENABLE_STRIKETHROUGH, markdown.strikethrough);
+    options.set(Options::ENABLE_TASKLISTS, markdown.tasklists);
+    options.set(
+        Options::ENABLE_SMART_PUNCTUATION,
+        markdown.smart_punctuation,
+    );
+    options.set(
+        Options::ENABLE_HEADING_ATTRIBUTES,
+        markdown.heading_attributes,
+    );
+    options.set(Options::ENABLE_DEFINITION_LIST, markdown.definition_lists);
+    options.set(Options::ENABLE_MATH, markdown.math);
+    options
+}
+
+/// Returns the Markdown file at `markdown_path`, like `pages/guides/intro.md`,
+/// relative to `pages/`.
+fn page_path(markdown_path: &Path) -> PathBuf {
+    markdown_path
+        .strip_prefix("pages")
+        .unwrap_or(markdown_path)
+        .to_path_buf()
+}
+
+/// Returns the Markdown file of the page being rendered with `context`,
+/// relative to `pages/`, from its `current_page`. See `page_context`.
+fn current_page_path(context: &tera::Context) -> PathBuf {
+    context
+        .get("current_page")
+        .and_then(|page| page.get("path"))
+        .and_then(|path| path.as_str())
+        .map(PathBuf::from)
+        .unwrap_or_default()
+}
+
+/// Returns where the page being rendered with `context` is written, relative
+/// to the site root, from its `current_page`. Links on the page are relative
+/// to it. See `page_context`.
+fn current_page_target(context: &tera::Context) -> PathBuf {
+    context
+        .get("current_page")
+        .and_then(|page| page.get("target_path"))
+        .and_then(|path| path.as_str())
+        .map(PathBuf::from)
+        .unwrap_or_default()
+}
+
+/// Returns the configuration to render a page with: `config`, with any
+/// `markdown` settings from the page's front matter applied on top.
+fn page_config(config: &Config, front_matter: &Value, markdown_path: &str) -> Result<Config> {
+    let mut config = config.clone();
+
+    let Some(Value::Object(overrides)) = front_matter.get("markdown") else {
+        return Ok(config);
+    };
+
+    let mut markdown = serde_json::to_value(&config.markdown)?;
+    if let Value::Object(settings) = &mut markdown {
+        settings.extend(overrides.clone());
     }
-    Ok(transformed.clone())
+    config.markdown = serde_json::from_value(markdown)
+        .map_err(|e| anyhow!("Invalid `markdown` front matter in '{markdown_path}': {e}"))?;
+    Ok(config)
 }
 
 /// Processes a markdown string into an HTML string.
-/// This includes expanding custom macros like [[ListPosts]].
-fn process_markdown_content(markdown: &str, config: &Config) -> Result<String> {
-    let mut options = pulldown_cmark::Options::empty();
-    options.insert(Options::ENABLE_TABLES);
+/// This includes expanding custom macros like [[ListPosts]] and shortcodes,
+/// which are rendered with `context`.
+fn process_markdown_content(
+    markdown: &str,
+    config: &Config,
+    tera: &Tera,
+    context: &tera::Context,
+) -> Result<String> {
+    let base = LinkBase::Page(&current_page_target(context));
+    render_markdown(markdown, config, tera, context, base).map(|(html, _)| html)
+}
+
+/// Like `process_markdown_content`, but Markdown that's a single paragraph
+/// isn't wrapped in `<p>`, so it can go inside a heading or a link.
+fn process_inline_markdown(
+    markdown: &str,
+    config: &Config,
+    tera: &Tera,
+    context: &tera::Context,
+) -> Result<String> {
+    let html = process_markdown_content(markdown, config, tera, context)?;
+    let paragraph = html
+        .trim_end()
+        .strip_prefix("<p>")
+        .and_then(|html| html.strip_suffix("</p>"))
+        .filter(|inner| !inner.contains("<p>") && !inner.contains("</p>"))
+        .map(str::to_string);
+    Ok(paragraph.unwrap_or(html))
+}
+
+/// Like `process_markdown_content`, but also returns the table of contents
+/// built from the headings, and links are relative to `base`.
+fn render_markdown(
+    markdown: &str,
+    config: &Config,
+    tera: &Tera,
+    context: &tera::Context,
+    base: LinkBase,
+) -> Result<(String, Vec<TocEntry>)> {
+    // Shortcodes are swapped for placeholders before parsing, so their
+    // arguments and bodies aren't interpreted as Markdown.
+    let (extracted, shortcodes, shortcode_map) = shortcodes::extract(markdown)?;
+    let (expanded, fenced_map) = admonitions::expand_fenced(&extracted);
+    let source = Source::new(markdown, vec![shortcode_map, fenced_map]);
+    let markdown = expanded;
+
+    let options = markdown_options(&config.markdown);
     // NOTE: We don't enable YAML metadata blocks here because the metadata
     // has already been stripped out and processed separately.
 
-    let parser = Parser::new_ext(markdown, options);
+    let parser = Parser::new_ext(&markdown, options);
+    // Links and math are handled first, while events still know where they
+    // came from, so errors can point at the line.
+    let events = links::resolve_links(
+        parser.into_offset_iter(),
+        &markdown,
+        &source,
+        &current_page_path(context),
+        base,
+        &config.link_targets,
+    )?;
+    let events = if config.markdown.math {
+        math::render_math(events.into_iter(), &source)?
+    } else {
+        events.into_iter().map(|(event, _)| event).collect()
+    };
     // The TextMergeStream is used to handle our custom macro `[[ListPosts]]`.
-    let iterator_with_macros = TextMergeStream::new(parser);
-    let events = expand_macros(iterator_with_macros, config)?;
+    let iterator_with_macros = TextMergeStream::new(events.into_iter());
+    let events = expand_macros(iterator_with_macros, config, tera, base)?;
+    // Shortcodes go in before heading IDs are made, so their output isn't
+    // part of an ID or the table of contents.
+    let rendered_shortcodes = shortcodes
+        .iter()
+        .map(|shortcode| {
+            render_shortcode(shortcode, config, tera, context, base)
+                .map_err(|e| offset_error(e, source.text(), shortcode.body_start))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let events = shortcodes::substitute(events, &rendered_shortcodes);
+    let (events, toc) = toc::add_heading_ids(events, config.markdown.heading_anchors);
+    let events = if config.highlight.enabled {
+        highlight::highlight_code_blocks(events, &config.highlight)?
+    } else {
+        events
+    };
+    // Admonitions go last, so their bodies are already fully processed.
+    let events = admonitions::render_admonitions(events, tera)?;
 
     let mut html_output = String::new();
     pulldown_cmark::html::push_html(&mut html_output, events.into_iter());
-    Ok(html_output)
+    Ok((html_output, toc))
+}
+
+/// Returns where `part`, a slice of `whole`, starts in it.
+fn body_offset(whole: &str, part: &str) -> usize {
+    part.as_ptr() as usize - whole.as_ptr() as usize
+}
+
+/// Moves a math, link or shortcode error in Markdown that starts at byte
+/// `offset` of `outer`, like a page's body or a shortcode's, to its line in
+/// `outer`. Other errors are returned as they are.
+fn offset_error(e: anyhow::Error, outer: &str, offset: usize) -> anyhow::Error {
+    let (line, column) = source_map::position(outer, offset);
+    match e.downcast::<MathError>() {
+        Ok(mut math_error) => {
+            if math_error.line == 1 {
+                math_error.column += column - 1;
+            }
+            math_error.line += line - 1;
+            anyhow::Error::new(math_error)
+        }
+        Err(e) => match e.downcast::<LinkError>() {
+            Ok(mut link_error) => {
+                link_error.line += line - 1;
+                anyhow::Error::new(link_error)
+            }
+            Err(e) => match e.downcast::<ShortcodeError>() {
+                Ok(mut shortcode_error) => {
+                    shortcode_error.line += line - 1;
+                    anyhow::Error::new(shortcode_error)
+                }
+                Err(e) => e,
+            },
+        },
+    }
+}
+
+/// Renders a page's summary with `context`, the page's template context: the
+/// `summary` from its front matter, or else the start of the page. See
+/// `split_summary`. Summaries are shown on other pages, so their links start
+/// from the site root, like `/blog/about.html`.
+fn render_summary(
+    page: &Page,
+    tera: &Tera,
+    config: &Config,
+    context: &tera::Context,
+) -> Result<String> {
+    let config = &page_config(config, &page.front_matter, &page.markdown_path)?;
+    let context = &markdown_context(context, &page.front_matter);
+    let _rendering = rendering(config, context);
+    let markdown = match page.front_matter.get("summary").and_then(|v| v.as_str()) {
+        Some(summary) => summary.trim(),
+        None => {
+            let (_, body) = split_document(&page.markdown_content)?;
+            split_summary(body, config.summary_words, &shortcodes::spans(body)?)
+        }
+    };
+    let base = LinkBase::Root(&config.base_path);
+    let rendered = render_markdown(markdown, config, tera, context, base);
+    rendered.map(|(html, _)| html).map_err(|e| {
+        // Errors in the page's own Markdown point at a line of the body.
+        let e = if page.front_matter.get("summary").is_some() {
+            e
+        } else {
+            let offset = body_offset(&page.markdown_content, markdown);
+            offset_error(e, &page.markdown_content, offset)
+        };
+        anyhow!(
+            "Failed to render the summary of '{}': {e}",
+            page.markdown_path
+        )
+    })
 }
 
 /// Renders a single page from its full markdown content to an HTML string.
 /// This function handles front matter parsing, content processing, and template rendering.
 fn render_page_html(
-    full_markdown_content: &str,
-    markdown_path: &str,
+    page: &Page,
     tera: &Tera,
     config: &Config,
     // The global_context contains site-wide variables like the section navigation.
     global_context: &tera::Context,
+    // The template for pages without a `template` of their own. See `Section::page_template`.
+    default_template: Option<&str>,
 ) -> Result<String> {
-    // 1. Split document into front matter and main content.
-    let (front_matter_str, main_content_str) = split_document(full_markdown_content)?;
-
-    // 2. Parse front matter YAML into a serde_json::Value. This allows us to inspect it.
-    let front_matter_value = match front_matter_str {
-        Some(yaml_str) => {
-            let value: Value = serde_yaml::from_str(yaml_str)
-                .map_err(|e| anyhow!("Failed to parse YAML for '{markdown_path}': {e}"))?;
-            Some(value)
-        }
-        None => None,
-    };
+    // 1. Split document into front matter and main content. The front matter
+    // was already parsed by `page_front_matter`.
+    let (full_markdown_content, markdown_path, front_matter) = (
+        &page.markdown_content,
+        &page.markdown_path,
+        &page.front_matter,
+    );
+    let (_, main_content_str) = split_document(full_markdown_content)?;
 
-    // 3. Create the initial Tera context from the parsed value.
-    let mut context = match &front_matter_value {
-        Some(value) => tera::Context::from_value(value.clone())?,
-        None => tera::Context::new(),
+    // 2. Pages can override the site's Markdown settings.
+    let page_config = page_config(config, front_matter, markdown_path)?;
+    let config = &page_config;
+
+    // 3. Create the initial Tera context from the front matter.
+    let mut context = match front_matter {
+        Value::Null => tera::Context::new(),
+        value => tera::Context::from_value(value.clone())?,
     };
 
     // 4. Process the main markdown body and add it to the context.
-    let main_html = process_markdown_content(main_content_str, config)?;
+    let markdown_context = markdown_context(global_context, front_matter);
+    let _rendering = rendering(config, &markdown_context);
+    let page_target = current_page_target(global_context);
+    let base = LinkBase::Page(&page_target);
+    let (main_html, toc) = render_markdown(main_content_str, config, tera, &markdown_context, base)
+        .map_err(|e| {
+            // Math, link and shortcode errors point at a line of the body; make
+            // it a line of the file.
+            let body_offset = body_offset(full_markdown_content, main_content_str);
+            let e = offset_error(e, full_markdown_content, body_offset);
+            anyhow!("Failed to render '{markdown_path}': {e}")
+        })?;
     context.insert("content", &main_html);
+    context.insert("toc", &toc);
+    context.insert(
+        "backlinks",
+        &config.link_targets.backlinks(
+            &page_path(Path::new(markdown_path)),
+            &current_page_target(global_context),
+        ),
+    );
+
+    // The summary, from the front matter or the page, was rendered already;
+    // see `render_summaries`.
+    context.insert("summary", &page.summary);
 
-    // 5. Process other string values from the front matter as markdown.
-    let mut modifications: Vec<(String, String)> = Vec::new();
-    if let Some(Value::Object(map)) = &front_matter_value {
+    // 5. Render the front matter fields configured as Markdown to HTML. Other
+    // values are left as written, so templates escape them like any other text.
+    if let Value::Object(map) = front_matter {
         for (key, val) in map {
-            // The `template` key is special and should not be processed as markdown.
-            if key == "template" {
+            let Some(val_str) = val.as_str().filter(|_| key != "summary") else {
                 continue;
-            }
-            if let Some(val_str) = val.as_str() {
-                let processed_val = process_markdown_content(val_str.trim(), config)?;
-                modifications.push((key.clone(), processed_val));
-            }
+            };
+            let html = if config.markdown_fields.contains(key) {
+                process_markdown_content(val_str.trim(), config, tera, &markdown_context)?
+            } else if config.inline_markdown_fields.contains(key) {
+                process_inline_markdown(val_str.trim(), config, tera, &markdown_context)?
+            } else {
+                continue;
+            };
+            context.insert(key, &html);
         }
     }
 
-    // Apply the modifications back to the context.
-    for (key, processed_val) in modifications {
-        context.insert(key, &processed_val);
-    }
-
-    // 6. Get template name from the context (it was excluded from markdown processing).
+    // 6. Get the template name from the front matter, or else the default
+    // for the page's section or site.
     let template_name = context
         .get("template")
         .and_then(|v| v.as_str())
+        .or(default_template)
         .map(|s| s.to_string());
 
     // 7. Merge with the global context. The page-specific context will override globals.
@@ -182,27 +692,26 @@ fn render_page_html(
 }
 
 /// Takes the rendered HTML and writes it to the final destination file.
+/// `context` is the page's template context; see `page_context`.
 fn write_page(
-    full_markdown_content: &str, // The full content of the .md file
-    markdown_path: &str,
-    target_path: &PathBuf,
+    page: &Page,
+    section: &Section,
+    target_path: &Path,
     tera: &Tera,
     config: &Config,
-    global_context: &tera::Context,
-    page_title: &str,
+    context: &tera::Context,
 ) -> Result<()> {
     // We now call our new, powerful render function.
-    let mut context_for_page = global_context.clone();
-    context_for_page.insert("page_title", page_title);
     let rendered_html = render_page_html(
-        full_markdown_content,
-        markdown_path,
+        page,
         tera,
         config,
-        &context_for_page,
+        context,
+        section.page_template.as_deref(),
     )?;
 
     // Create parent directories if they don't exist.
+    let target_path = config.output_dir.join(target_path);
     if let Some(parent) = target_path.parent() {
         fs::create_dir_all(parent)?;
     }
@@ -233,11 +742,11 @@ This is an example post.
 const INITIAL_INDEX_TEMPLATE: &str = r#"<!DOCTYPE html>
 <html>
 <head>
-  <title>{{ title | safe }}</title>
+  <title>{{ title }}</title>
 </head>
 <body>
   <header>
-    <h1>{{ title | safe }}</h1>
+    <h1>{{ title }}</h1>
   </header>
   <main>
     {{ content | safe }}
@@ -245,7 +754,7 @@ const INITIAL_INDEX_TEMPLATE: &str = r#"<!DOCTYPE html>
 </body>
 </html>"#;
 const INITIAL_POST_TEMPLATE: &str =
-    "<!DOCTYPE html><head><title>{{ title | safe }}</title></head><body>{{ content | safe }}</body></html>";
+    "<!DOCTYPE html><head><title>{{ title }}</title></head><body>{{ content | safe }}</body></html>";
 
 const INITIAL_CONFIG: &str = r#"# The homepage is the page that will be rendered at the root of the site.
 # It is relative to the `pages` directory.
@@ -296,11 +805,25 @@ struct Page {
     markdown_content: String,
     markdown_path: String,
     title: String,
+    /// The page's summary, rendered to HTML. See `split_summary`.
+    summary: String,
+    /// The parsed front matter, or `null` if the page has none.
+    front_matter: Value,
 }
 
 #[derive(Debug, Serialize)]
 struct Section {
     title: String,
+    /// The `description` from the section's `_index.md`.
+    description: Option<String>,
+    /// The section's directory, relative to `pages/`.
+    path: PathBuf,
+    /// The section's own page, from its `_index.md`, which isn't in `pages`.
+    index: Option<Page>,
+    /// The template for pages in the section without their own `template`:
+    /// the `page_template` from its `_index.md`, or else its parent's, or else
+    /// the site's `default_template`.
+    page_template: Option<String>,
     pages: Vec<Page>,
     subsections: Vec<Section>,
     order: usize,
@@ -323,7 +846,7 @@ fn capitalize_string(s: &str) -> String {
     result
 }
 
-fn parse_order_from_pathbuf(path: &PathBuf) -> Option<usize> {
+fn parse_order_from_pathbuf(path: &Path) -> Option<usize> {
     if let Some(file_name) = path.file_name() {
         if let Some(file_str) = file_name.to_str() {
             if let Some(dash_index) = file_str.find('-') {
@@ -339,35 +862,220 @@ fn parse_order_from_pathbuf(path: &PathBuf) -> Option<usize> {
     None
 }
 
+/// Returns a section's title from its directory name, like `Getting Started`
+/// for `01-getting-started`.
+fn section_title(dir: &Path) -> String {
+    let name = dir.file_name().and_then(|s| s.to_str()).unwrap_or("");
+    let name = match name.split_once('-') {
+        Some((prefix, rest)) if !prefix.is_empty() && prefix.parse::<u32>().is_ok() => rest,
+        _ => name,
+    };
+    capitalize_string(&name.replace("-", " "))
+}
+
+/// Returns where the page at `markdown_path` is written, relative to the site
+/// root. A section's `_index.md` is written to its `index.html`, and with
+/// `pretty_urls`, other pages are written to an `index.html` of their own,
+/// like `about/index.html` for `about.md`.
+fn page_target_path(markdown_path: &Path, pretty_urls: bool) -> Result<PathBuf> {
+    let relative = markdown_path.strip_prefix("pages")?;
+    match relative.file_stem().and_then(|s| s.to_str()) {
+        Some("_index" | "index") => Ok(relative.with_file_name("index.html")),
+        Some(stem) if pretty_urls => Ok(relative.with_file_name(stem).join("index.html")),
+        _ => Ok(relative.with_extension("html")),
+    }
+}
+
+/// Returns a page's title: the `title` in its front matter, or else its file
+/// name, like `Getting Started` for `getting-started.md`. A section's
+/// `_index.md` is titled after the section.
+fn page_title(path: &Path, front_matter: &Value) -> String {
+    match front_matter.get("title") {
+        Some(Value::String(title)) => title.clone(),
+        _ if path.file_stem().and_then(|s| s.to_str()) == Some("_index") => {
+            section_title(path.parent().unwrap_or(Path::new("")))
+        }
+        _ => capitalize_string(
+            path.file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("")
+                .replace("-", " ")
+                .as_str(),
+        ),
+    }
+}
+
+/// Sorts a section's pages: first by `weight` (or `order`) in their front
+/// matter, lightest first, then by a numeric prefix on their file name, like
+/// `01-intro.md`, then by `sort_by` from the section's `_index.md`: `title`,
+/// the default, or `date`, newest first. Pages missing a key sort after those
+/// with one. Ties keep a stable order by file path.
+fn sort_pages(pages: &mut [Page], sort_by: &str) -> Result<()> {
+    if sort_by != "title" && sort_by != "date" {
+        return Err(anyhow!(
+            "Unknown `sort_by: {sort_by}`; use `title` or `date`"
+        ));
+    }
+    let weight = |page: &Page| {
+        page.front_matter
+            .get("weight")
+            .or_else(|| page.front_matter.get("order"))
+            .and_then(|v| v.as_f64())
+    };
+    let prefix = |page: &Page| parse_order_from_pathbuf(Path::new(&page.markdown_path));
+    fn date(page: &Page) -> Option<&str> {
+        page.front_matter.get("date").and_then(|v| v.as_str())
+    }
+    if sort_by == "date" {
+        for page in pages.iter() {
+            if let Some(date) = date(page).filter(|date| data::parse_date(date).is_none()) {
+                return Err(anyhow!(
+                    "`date: {date}` in '{}' isn't a date like `2024-05-05`",
+                    page.markdown_path
+                ));
+            }
+        }
+    }
+    // Sorts `Some`s in order, before `None`s.
+    fn some_first<T: PartialOrd>(a: Option<T>, b: Option<T>) -> std::cmp::Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal),
+            (a, b) => a.is_none().cmp(&b.is_none()),
+        }
+    }
+
+    pages.sort_by(|a, b| {
+        some_first(weight(a), weight(b))
+            .then_with(|| some_first(prefix(a), prefix(b)))
+            .then_with(|| match sort_by {
+                // Newest first, but still with undated pages last.
+                "date" => match (
+                    date(a).and_then(data::parse_date),
+                    date(b).and_then(data::parse_date),
+                ) {
+                    (Some(a), Some(b)) => b.cmp(&a),
+                    (a, b) => a.is_none().cmp(&b.is_none()),
+                },
+                _ => std::cmp::Ordering::Equal,
+            })
+            .then_with(|| a.title.cmp(&b.title))
+            .then_with(|| a.markdown_path.cmp(&b.markdown_path))
+    });
+    Ok(())
+}
+
+/// Parses a page's front matter and applies the cascade to it: first the
+/// values of every `cascade` rule in `PUSHPIN.yaml` whose glob matches the page,
+/// then `cascade`, the values from its sections' `_index.md` files, and last
+/// the page's own values, which win. Returns `null` if there's nothing to apply.
+fn page_front_matter(
+    front_matter: Option<FrontMatter>,
+    path: &Path,
+    cascade: &Map<String, Value>,
+    config: &Config,
+) -> Result<Value> {
+    let own = match front_matter {
+        Some(front_matter) => front_matter
+            .parse()
+            .map_err(|e| anyhow!("Failed to parse front matter for '{}': {e}", path.display()))?,
+        None => Value::Null,
+    };
+
+    let relative = path.strip_prefix("pages").unwrap_or(path);
+    let mut front_matter = Map::new();
+    for rule in &config.cascade {
+        if rule.pattern.is_match(relative) {
+            front_matter.extend(rule.values.clone());
+        }
+    }
+    front_matter.extend(cascade.clone());
+    match own {
+        Value::Object(own) => front_matter.extend(own),
+        Value::Null if front_matter.is_empty() => return Ok(Value::Null),
+        Value::Null => {}
+        _ => {
+            return Err(anyhow!(
+                "Failed to parse front matter for '{}': it must be keys and values",
+                path.display()
+            ))
+        }
+    }
+    Ok(Value::Object(front_matter))
+}
+
+/// Reads the page at `path`, with `cascade` applied to its front matter. Its
+/// summary is left empty; see `render_summaries`.
+fn read_page(path: &Path, cascade: &Map<String, Value>, config: &Config) -> Result<Page> {
+    let content = fs::read_to_string(path)?;
+    let (raw_front_matter, _) = split_document(&content)
+        .map_err(|e| anyhow!("Failed to parse front matter for '{}': {e}", path.display()))?;
+    let front_matter = page_front_matter(raw_front_matter, path, cascade, config)?;
+    Ok(Page {
+        target_path: page_target_path(path, config.pretty_urls)?,
+        title: page_title(path, &front_matter),
+        markdown_path: path.to_str().ok_or(anyhow!("file name error"))?.to_string(),
+        markdown_content: content,
+        // Rendered by `render_summaries`, once every page is known.
+        summary: String::new(),
+        front_matter,
+    })
+}
+
+/// Returns what a section cascades to the pages in it: `inherited`, from the
+/// sections around it, with the `cascade` from its `_index.md` on top.
+fn section_cascade(
+    index_front_matter: &Value,
+    inherited: &Map<String, Value>,
+) -> Map<String, Value> {
+    let mut cascade = inherited.clone();
+    if let Some(Value::Object(values)) = index_front_matter.get("cascade") {
+        cascade.extend(values.clone());
+    }
+    cascade
+}
+
 /// Traverses the `pages` directory and builds a tree structure of sections and pages.
-fn parse_sections(dir: &PathBuf, config: &Config) -> Result<Section> {
-    let order = parse_order_from_pathbuf(dir).unwrap_or(0);
+/// `inherited_template` is the parent section's `page_template`, and
+/// `inherited_cascade` what the sections around this one cascade to it.
+fn parse_sections(
+    dir: &PathBuf,
+    inherited_template: Option<&str>,
+    inherited_cascade: &Map<String, Value>,
+    config: &Config,
+) -> Result<Section> {
+    // A section's `_index.md` only gets what the sections around it cascade,
+    // and sets the section's title, description, order and cascade.
+    let index_path = dir.join("_index.md");
+    let index = match index_path.is_file() {
+        true => Some(read_page(&index_path, inherited_cascade, config)?),
+        false => None,
+    };
+    let index_front_matter = index
+        .as_ref()
+        .map_or(&Value::Null, |index| &index.front_matter);
+    let cascade = section_cascade(index_front_matter, inherited_cascade);
+    let string = |key: &str| {
+        index_front_matter
+            .get(key)
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+    };
 
     let mut section = Section {
-        title: dir
-            .file_name()
-            .ok_or(anyhow!("file name error"))?
-            .to_str()
-            .map(|s| {
-                if let Some(dash_index) = s.find('-') {
-                    if dash_index > 0 {
-                        let prefix = &s[..dash_index];
-                        if prefix.parse::<u32>().is_ok() {
-                            return s[dash_index + 1..].to_string();
-                        }
-                    }
-                }
-                s.to_string()
-            })
-            .as_ref()
-            .map(|s| s.replace("-", " "))
-            .as_ref()
-            .map(|s| capitalize_string(s))
-            .ok_or(anyhow!("file name error"))?
-            .to_string(),
+        title: string("title").unwrap_or_else(|| section_title(dir)),
+        description: string("description"),
+        path: dir.strip_prefix("pages")?.to_path_buf(),
+        page_template: string("page_template").or(inherited_template.map(|s| s.to_string())),
         pages: vec![],
         subsections: vec![],
-        order,
+        order: index_front_matter
+            .get("order")
+            .and_then(|v| v.as_u64())
+            .map_or_else(
+                || parse_order_from_pathbuf(dir).unwrap_or(0),
+                |order| order as usize,
+            ),
+        index,
     };
 
     let mut pages = vec![];
@@ -377,102 +1085,541 @@ fn parse_sections(dir: &PathBuf, config: &Config) -> Result<Section> {
         let entry = entry?;
         let path = entry.path();
         if path.is_dir() {
-            let subsection = parse_sections(&path, config)?;
+            let subsection =
+                parse_sections(&path, section.page_template.as_deref(), &cascade, config)?;
             subsections.push(subsection);
-        } else if path.extension().and_then(|s| s.to_str()) == Some("md") {
-            let mut file = File::open(&path)?;
-            let mut content = String::new();
-            file.read_to_string(&mut content)?;
+        } else if path.extension().and_then(|s| s.to_str()) == Some("md") && path != index_path {
+            pages.push(read_page(&path, &cascade, config)?);
+        }
+    }
 
-            let target_path = path.strip_prefix("pages")?;
-            let target_path = target_path.with_extension("html");
+    let sort_by = section
+        .index
+        .as_ref()
+        .and_then(|index| index.front_matter.get("sort_by"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("title");
+    sort_pages(&mut pages, sort_by)
+        .map_err(|e| anyhow!("Failed to sort 'pages/{}': {e}", section.path.display()))?;
+    section.pages = pages;
+    section.subsections = subsections;
+    section
+        .subsections
+        .sort_by(|a, b| a.order.cmp(&b.order).then(a.path.cmp(&b.path)));
 
-            let (front_matter_str, _) = split_document(&content)?;
-            let front_matter_value = if let Some(yaml_str) = front_matter_str {
-                serde_yaml::from_str::<serde_json::Value>(yaml_str).ok()
-            } else {
-                None
-            };
+    Ok(section)
+}
 
-            let title = if let Some(Value::Object(map)) = front_matter_value {
-                if let Some(Value::String(t)) = map.get("title") {
-                    t.clone()
-                } else {
-                    capitalize_string(
-                        path.file_stem()
-                            .and_then(|s| s.to_str())
-                            .unwrap_or("")
-                            .replace("-", " ")
-                            .as_str(),
-                    )
-                }
-            } else {
-                path.file_stem()
-                    .and_then(|s| s.to_str())
-                    .map(|s| capitalize_string(s.replace("-", " ").as_str()))
-                    .unwrap_or("".to_string())
-            };
+/// Fails if two pages in `section`, or the sections inside it, are written to
+/// the same file, like `_index.md` and `index.md`, or with pretty URLs,
+/// `about.md` and `about/index.md`. `written` maps the files already seen to
+/// the pages written to them.
+fn check_target_paths<'s>(
+    section: &'s Section,
+    written: &mut HashMap<&'s Path, &'s str>,
+) -> Result<()> {
+    for page in section.index.iter().chain(&section.pages) {
+        if let Some(other) = written.insert(&page.target_path, &page.markdown_path) {
+            return Err(anyhow!(
+                "Both '{other}' and '{}' generate '{}'",
+                page.markdown_path,
+                page.target_path.display()
+            ));
+        }
+    }
+    for subsection in &section.subsections {
+        check_target_paths(subsection, written)?;
+    }
+    Ok(())
+}
 
-            let page = Page {
-                target_path,
-                markdown_content: content,
-                markdown_path: path.to_str().ok_or(anyhow!("file name error"))?.to_string(),
-                title,
-            };
+/// Returns a page's table of contents and links without rendering the page,
+/// so links to it can be checked, and its backlinks found, before it's rendered.
+fn scan_page(markdown: &str, config: &Config) -> Result<(Vec<TocEntry>, Vec<links::RawLink>)> {
+    let (markdown, shortcodes, _) = shortcodes::extract(markdown)?;
+    let (markdown, _) = admonitions::expand_fenced(&markdown);
+    let parser = Parser::new_ext(&markdown, markdown_options(&config.markdown));
+    let events: Vec<_> = parser.into_offset_iter().collect();
+    let raw_links = links::raw_links(&events, &markdown);
+    let events = TextMergeStream::new(events.into_iter().map(|(event, _)| event)).collect();
+    // Shortcodes aren't rendered yet, but their output isn't part of heading
+    // IDs anyway, so they're left out the same way.
+    let events = shortcodes::substitute(events, &vec![String::new(); shortcodes.len()]);
+    Ok((toc::add_heading_ids(events, false).1, raw_links))
+}
 
-            pages.push(page);
+/// Adds every page in `section` and the sections inside it to `targets`, so
+/// pages can link to each other, and collects each page's links into
+/// `raw_links`.
+fn collect_link_targets(
+    section: &Section,
+    config: &Config,
+    targets: &mut LinkTargets,
+    raw_links: &mut Vec<(PathBuf, Vec<links::RawLink>)>,
+) -> Result<()> {
+    for page in section.index.iter().chain(&section.pages) {
+        let markdown_path = &page.markdown_path;
+        let (_, main_content_str) = split_document(&page.markdown_content)?;
+        let page_config = page_config(config, &page.front_matter, markdown_path)?;
+        let (toc, page_links) = scan_page(main_content_str, &page_config).map_err(|e| {
+            let body_offset = body_offset(&page.markdown_content, main_content_str);
+            let e = offset_error(e, &page.markdown_content, body_offset);
+            anyhow!("Failed to render '{markdown_path}': {e}")
+        })?;
+        let path = page_path(Path::new(markdown_path));
+        targets.insert(
+            path.clone(),
+            page.target_path.clone(),
+            page.title.clone(),
+            &toc,
+        );
+        raw_links.push((path, page_links));
+    }
+    for subsection in &section.subsections {
+        collect_link_targets(subsection, config, targets, raw_links)?;
+    }
+    Ok(())
+}
+
+/// Adds the Markdown files in `section` and the sections inside it with no
+/// `template`, whether in their own front matter, cascaded from their
+/// sections, or from a section's `page_template` or the site's
+/// `default_template`, to `pages`. They're written out as bare HTML fragments.
+fn find_pages_without_template(section: &Section, pages: &mut Vec<PathBuf>) {
+    for page in section.index.iter().chain(&section.pages) {
+        if section.page_template.is_none() && page.front_matter.get("template").is_none() {
+            pages.push(PathBuf::from(&page.markdown_path));
         }
     }
+    for subsection in &section.subsections {
+        find_pages_without_template(subsection, pages);
+    }
+}
 
-    section.pages = pages;
-    section.subsections = subsections;
-    section.subsections.sort_by(|a, b| a.order.cmp(&b.order));
+/// Finds the section whose directory (relative to `pages/`) is `path`.
+fn find_section<'a>(root: &'a Section, path: &Path) -> Option<&'a Section> {
+    if root.path == path {
+        return Some(root);
+    }
+    root.subsections
+        .iter()
+        .find_map(|subsection| find_section(subsection, path))
+}
 
-    Ok(section)
+/// Finds the page generated from the Markdown file at `markdown_path`.
+fn find_page<'a>(root: &'a Section, markdown_path: &Path) -> Option<&'a Page> {
+    root.index
+        .iter()
+        .chain(&root.pages)
+        .find(|page| Path::new(&page.markdown_path) == markdown_path)
+        .or_else(|| {
+            root.subsections
+                .iter()
+                .find_map(|subsection| find_page(subsection, markdown_path))
+        })
+}
+
+/// Collects the items a paginated page iterates over, as named by its
+/// `paginate` front matter key:
+/// - `section` (the default): the other pages in the page's own section.
+/// - `posts`: the `posts` list from `PUSHPIN.yaml`.
+/// - `taxonomy:<kind>`: the terms of a taxonomy, like `taxonomy:tags`, grouped
+///   like `get_taxonomy`.
+/// - anything else: the pages of the section at that path, relative to `pages/`.
+fn collect_paginated_items(
+    page: &Page,
+    section: &Section,
+    root: &Section,
+    config: &Config,
+) -> Result<Vec<Value>> {
+    let collection = page
+        .front_matter
+        .get("paginate")
+        .and_then(|v| v.as_str())
+        .unwrap_or("section");
+
+    let items = match collection {
+        "posts" => config
+            .posts
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?,
+        "section" => section
+            .pages
+            .iter()
+            .filter(|p| p.markdown_path != page.markdown_path)
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?,
+        _ if collection.starts_with("taxonomy:") => {
+            let kind = collection["taxonomy:".len()..].trim();
+            if kind.is_empty() {
+                return Err(anyhow!(
+                    "Failed to paginate '{}': `taxonomy:` needs a front matter field, like `taxonomy:tags`",
+                    page.markdown_path
+                ));
+            }
+            site_functions::taxonomy(&serde_json::to_value(root)?, kind)
+        }
+        path => {
+            let target = find_section(root, Path::new(path.trim_matches('/'))).ok_or(anyhow!(
+                "Failed to paginate '{}': no section at 'pages/{path}'",
+                page.markdown_path
+            ))?;
+            target
+                .pages
+                .iter()
+                .map(serde_json::to_value)
+                .collect::<Result<Vec<_>, _>>()?
+        }
+    };
+    Ok(items)
+}
+
+/// A page or section in a page's navigation: its `previous` and `next`
+/// pages, `parent` section and `breadcrumbs`.
+#[derive(Debug, Serialize)]
+struct NavLink {
+    title: String,
+    /// The URL, relative to the current page. Sections have none unless they
+    /// have an `_index.md` or `index.md`.
+    url: Option<String>,
+    /// Where the page is written, relative to the site root.
+    target_path: Option<PathBuf>,
+}
+
+impl NavLink {
+    /// Links to `page` from the page written to `from`. See `links::page_url`.
+    fn page(page: &Page, from: &Path, pretty_urls: bool) -> NavLink {
+        NavLink {
+            title: page.title.clone(),
+            url: Some(links::page_url(from, &page.target_path, pretty_urls)),
+            target_path: Some(page.target_path.clone()),
+        }
+    }
+
+    fn section(section: &Section, from: &Path, pretty_urls: bool) -> NavLink {
+        let index_path = section.path.join("index.html");
+        let index = section
+            .index
+            .iter()
+            .chain(&section.pages)
+            .find(|page| page.target_path == index_path);
+        match index {
+            Some(index) => NavLink {
+                title: section.title.clone(),
+                ..NavLink::page(index, from, pretty_urls)
+            },
+            None => NavLink {
+                title: section.title.clone(),
+                url: None,
+                target_path: None,
+            },
+        }
+    }
+}
+
+/// Adds a page's navigation to `context`: its `previous` and `next` pages in
+/// `section`, its `parent` section and the `breadcrumbs` leading to it. URLs
+/// are relative to `target_path`, where this copy of the page is written, and
+/// written as directories with `pretty_urls`.
+///
+/// A section's `_index.md` comes before the section's other pages, and its
+/// parent is the section containing its section.
+fn insert_navigation(
+    context: &mut tera::Context,
+    page: &Page,
+    section: &Section,
+    root: &Section,
+    target_path: &Path,
+    pretty_urls: bool,
+) {
+    let is_index = section
+        .index
+        .as_ref()
+        .is_some_and(|index| index.markdown_path == page.markdown_path);
+    let siblings: Vec<&Page> = section.index.iter().chain(&section.pages).collect();
+    let position = siblings
+        .iter()
+        .position(|sibling| sibling.markdown_path == page.markdown_path);
+    let previous = position
+        .and_then(|i| i.checked_sub(1))
+        .map(|i| NavLink::page(siblings[i], target_path, pretty_urls));
+    let next = position
+        .and_then(|i| siblings.get(i + 1))
+        .map(|sibling| NavLink::page(sibling, target_path, pretty_urls));
+
+    // Every section from the root down to the page's parent.
+    let mut ancestors = vec![root];
+    let mut path = PathBuf::new();
+    for component in section.path.components() {
+        path.push(component);
+        if let Some(ancestor) = find_section(root, &path) {
+            ancestors.push(ancestor);
+        }
+    }
+    if is_index {
+        ancestors.pop();
+    }
+    let breadcrumbs: Vec<NavLink> = ancestors
+        .iter()
+        .map(|ancestor| NavLink::section(ancestor, target_path, pretty_urls))
+        .collect();
+
+    context.insert("previous", &previous);
+    context.insert("next", &next);
+    context.insert("parent", &breadcrumbs.last());
+    context.insert("breadcrumbs", &breadcrumbs);
+}
+
+/// Returns the template context for `page`, which is in `section` and
+/// written to `target_path`: the site-wide `context`, plus the page's title,
+/// `current_page`, its navigation, and for a section's own page, `section`.
+fn page_context(
+    page: &Page,
+    section: &Section,
+    root: &Section,
+    target_path: &Path,
+    config: &Config,
+    context: &tera::Context,
+) -> tera::Context {
+    let mut context = context.clone();
+    // A section's own page can list what's in it through `section`.
+    if section
+        .index
+        .as_ref()
+        .is_some_and(|index| index.markdown_path == page.markdown_path)
+    {
+        context.insert("section", section);
+    }
+    insert_navigation(
+        &mut context,
+        page,
+        section,
+        root,
+        target_path,
+        config.pretty_urls,
+    );
+
+    let current_page = CurrentPage {
+        path: page_path(Path::new(&page.markdown_path)),
+        url: links::page_url(Path::new(""), target_path, config.pretty_urls),
+        target_path: target_path.to_path_buf(),
+        title: page.title.clone(),
+        section: section.path.clone(),
+    };
+    context.insert("page_title", &page.title);
+    context.insert("current_page", &current_page);
+    context
+}
+
+/// Writes `page`, which is in `section`: once, or once per page of its
+/// collection if it has `paginate_by`. Returns the number of files written.
+fn write_section_page(
+    page: &Page,
+    section: &Section,
+    root: &Section,
+    tera: &Tera,
+    config: &Config,
+    context: &tera::Context,
+) -> Result<usize> {
+    // Pages with `paginate_by` are rendered once per page of their collection.
+    if let Some(paginate_by) = page
+        .front_matter
+        .get("paginate_by")
+        .and_then(|v| v.as_u64())
+    {
+        let items = collect_paginated_items(page, section, root, config)?;
+        let pagers = paginator::paginate(
+            items,
+            paginate_by as usize,
+            &page.target_path,
+            config.pretty_urls,
+        );
+        for pager in &pagers {
+            let mut context_for_pager =
+                page_context(page, section, root, &pager.target_path, config, context);
+            context_for_pager.insert("paginator", pager);
+            write_page(
+                page,
+                section,
+                &pager.target_path,
+                tera,
+                config,
+                &context_for_pager,
+            )?;
+        }
+        return Ok(pagers.len());
+    }
+
+    let context_for_page = page_context(page, section, root, &page.target_path, config, context);
+    write_page(
+        page,
+        section,
+        &page.target_path,
+        tera,
+        config,
+        &context_for_page,
+    )?;
+    Ok(1)
 }
 
 /// Recursively generates the HTML for all pages in all sections.
 fn generate_sections(
     sections: &Section,
+    root: &Section,
     tera: &Tera,
     config: &Config,
     context: &tera::Context,
 ) -> Result<usize> {
     let mut total = 0;
-    for page in &sections.pages {
-        write_page(
-            &page.markdown_content,
-            &page.markdown_path,
-            &page.target_path,
-            tera,
-            config,
-            context, // This is the global context
-            &page.title,
-        )?;
-        total += 1;
+
+    for page in sections.index.iter().chain(&sections.pages) {
+        total += write_section_page(page, sections, root, tera, config, context)?;
     }
 
     for subsection in &sections.subsections {
-        total += generate_sections(subsection, tera, config, context)?;
+        total += generate_sections(subsection, root, tera, config, context)?;
     }
 
     Ok(total)
 }
 
-/// The main entry point for site generation.
-pub(crate) fn generate(config: &Config) -> Result<usize> {
-    // Recursively parse the file structure in the `pages` directory.
-    let sections = parse_sections(&PathBuf::from("pages"), config)?;
+/// Renders the summary of every page in `sections` into `summaries`, keyed by
+/// Markdown path, with the same context `generate_sections` renders the page with.
+fn render_summaries(
+    sections: &Section,
+    root: &Section,
+    tera: &Tera,
+    config: &Config,
+    context: &tera::Context,
+    summaries: &mut HashMap<String, String>,
+) -> Result<()> {
+    for page in sections.index.iter().chain(&sections.pages) {
+        let page_context = page_context(page, sections, root, &page.target_path, config, context);
+        let summary = render_summary(page, tera, config, &page_context)?;
+        summaries.insert(page.markdown_path.clone(), summary);
+    }
+    for subsection in &sections.subsections {
+        render_summaries(subsection, root, tera, config, context, summaries)?;
+    }
+    Ok(())
+}
 
+/// Fills in each page's summary from `summaries`. See `render_summaries`.
+fn apply_summaries(section: &mut Section, summaries: &mut HashMap<String, String>) {
+    for page in section.index.iter_mut().chain(&mut section.pages) {
+        if let Some(summary) = summaries.remove(&page.markdown_path) {
+            page.summary = summary;
+        }
+    }
+    for subsection in &mut section.subsections {
+        apply_summaries(subsection, summaries);
+    }
+}
+
+/// What `generate` built.
+pub(crate) struct Generated {
+    /// The number of files written.
+    pub(crate) files: usize,
+    /// The Markdown files with no template, which were written as bare HTML
+    /// fragments. See `find_pages_without_template`.
+    pub(crate) pages_without_template: Vec<PathBuf>,
+}
+
+/// The main entry point for site generation.
+pub(crate) fn generate(config: &Config) -> Result<Generated> {
     // Initialize the Tera templating engine. Use `**` for recursive glob.
-    let tera =
+    let mut tera =
         Tera::new("templates/**/*.html").map_err(|e| anyhow!("Failed to initialize Tera: {e}"))?;
+    if !tera
+        .get_template_names()
+        .any(|name| name == LIST_POSTS_TEMPLATE)
+    {
+        tera.add_raw_template(LIST_POSTS_TEMPLATE, DEFAULT_LIST_POSTS_TEMPLATE)?;
+    }
+    filters::register_filters(&mut tera);
+    if !tera
+        .get_template_names()
+        .any(|name| name == admonitions::ADMONITION_TEMPLATE)
+    {
+        tera.add_raw_template(
+            admonitions::ADMONITION_TEMPLATE,
+            admonitions::DEFAULT_ADMONITION_TEMPLATE,
+        )?;
+    }
+
+    // Recursively parse the file structure in the `pages` directory.
+    let mut config = config.clone();
+    let mut sections = parse_sections(
+        &PathBuf::from("pages"),
+        config.default_template.as_deref(),
+        &Map::new(),
+        &config,
+    )?;
+    check_target_paths(&sections, &mut HashMap::new())?;
+
+    // Find every page and heading before rendering any, so links between
+    // pages can be resolved.
+    let mut link_targets = LinkTargets::new(config.pretty_urls);
+    let mut raw_links = vec![];
+    collect_link_targets(&sections, &config, &mut link_targets, &mut raw_links)?;
+    link_targets.add_backlinks(&raw_links);
+    config.link_targets = Arc::new(link_targets);
+
+    site_functions::register_functions(&mut tera, serde_json::to_value(&sections)?);
 
     // Create a global context and add the site structure to it.
     // This makes the `sections` variable available to all templates for navigation.
     let mut context = tera::Context::new();
     context.insert("sections", &sections);
+    context.insert("site", &config.site);
+    context.insert("data", &data::load_data_dir(Path::new("data"))?);
+
+    // Summaries are rendered like their pages, so they can use shortcodes and
+    // the site functions, but they can't see other pages' summaries. Once
+    // they're done, the templates get the sections with them.
+    let mut summaries = HashMap::new();
+    render_summaries(
+        &sections,
+        &sections,
+        &page_engine(tera.clone()),
+        &config,
+        &context,
+        &mut summaries,
+    )?;
+    apply_summaries(&mut sections, &mut summaries);
+    site_functions::register_functions(&mut tera, serde_json::to_value(&sections)?);
+    context.insert("sections", &sections);
+    let tera = page_engine(tera);
+
+    // Attach the URL and summary of each post's page, for `[[ListPosts]]` and
+    // templates.
+    for post in &mut config.posts {
+        let markdown_path = Path::new("pages").join(&post.path);
+        if let Some(page) = find_page(&sections, &markdown_path) {
+            post.url = links::page_url(Path::new(""), &page.target_path, config.pretty_urls);
+            post.target_path = page.target_path.clone();
+            post.summary = Some(page.summary.clone());
+        }
+    }
+    let config = &config;
+
+    // Class-based highlighting needs the theme's stylesheet next to the pages.
+    if config.highlight.enabled && !config.highlight.inline_styles {
+        let css_path = config.output_dir.join(&config.highlight.css_path);
+        if let Some(parent) = css_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(css_path, highlight::theme_css(&config.highlight)?)?;
+    }
+
+    let mut pages_without_template = vec![];
+    find_pages_without_template(&sections, &mut pages_without_template);
+    pages_without_template.sort();
 
     // Start the recursive generation process.
-    generate_sections(&sections, &tera, config, &context)
+    Ok(Generated {
+        files: generate_sections(&sections, &sections, &tera, config, &context)?,
+        pages_without_template,
+    })
 }
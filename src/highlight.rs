@@ -0,0 +1,273 @@
+use std::ops::RangeInclusive;
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Result};
+use pulldown_cmark::{CodeBlockKind, Event, Tag, TagEnd};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Color, Theme, ThemeSet};
+use syntect::html::{
+    css_for_theme_with_class_style, line_tokens_to_classed_spans, styled_line_to_highlighted_html,
+    ClassStyle, IncludeBackground,
+};
+use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+use crate::HighlightConfig;
+
+/// Prefixes every syntax class in highlighted code, so theme CSS can't clash
+/// with a site's own. The classes for the code block's structure, like
+/// `code-line`, are unprefixed so they can't clash with syntax classes.
+const CLASS_PREFIX: &str = "hl-";
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed {
+    prefix: CLASS_PREFIX,
+};
+
+/// The grammars bundled with syntect, loaded once per run.
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+/// The themes bundled with syntect, loaded once per run.
+fn theme_set() -> &'static ThemeSet {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    THEMES.get_or_init(ThemeSet::load_defaults)
+}
+
+/// Looks up a bundled theme by name.
+pub(crate) fn theme(name: &str) -> Result<&'static Theme> {
+    theme_set().themes.get(name).ok_or_else(|| {
+        let available: Vec<&str> = theme_set().themes.keys().map(|k| k.as_str()).collect();
+        anyhow!(
+            "Unknown highlight theme '{name}'; available themes are: {}",
+            available.join(", ")
+        )
+    })
+}
+
+fn css_color(color: Color) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+}
+
+/// Returns the stylesheet for class-based highlighting with the configured theme.
+pub(crate) fn theme_css(config: &HighlightConfig) -> Result<String> {
+    let theme = theme(&config.theme)?;
+    let mut css = css_for_theme_with_class_style(theme, CLASS_STYLE)?;
+    let line_highlight = theme
+        .settings
+        .line_highlight
+        .map(css_color)
+        .unwrap_or("rgba(255, 255, 0, 0.2)".to_string());
+    css.push_str(&format!(
+        "\n.code-line-highlighted {{\n background-color: {line_highlight};\n display: inline-block;\n width: 100%;\n}}\n"
+    ));
+    css.push_str(
+        ".code-line-number {\n opacity: 0.5;\n margin-right: 1em;\n user-select: none;\n}\n",
+    );
+    Ok(css)
+}
+
+/// The options in a fenced code block's info string, like `rust {2,4-6} linenos`.
+#[derive(Debug, Default)]
+struct CodeBlockInfo {
+    language: Option<String>,
+    highlighted_lines: Vec<RangeInclusive<usize>>,
+    /// Overrides the site's `line_numbers` setting for this block.
+    line_numbers: Option<bool>,
+}
+
+/// Parses a code block's info string. Line ranges go in braces, like `{2,4-6}`.
+fn parse_info(info: &str) -> Result<CodeBlockInfo> {
+    let mut parsed = CodeBlockInfo::default();
+
+    let mut rest = info.to_string();
+    if let Some(open) = info.find('{') {
+        let close = info[open..]
+            .find('}')
+            .ok_or(anyhow!("Unclosed line range in code block info '{info}'"))?
+            + open;
+        for range in info[open + 1..close].split(',').map(str::trim) {
+            if range.is_empty() {
+                continue;
+            }
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let parse_line = |n: &str| {
+                n.trim().parse::<usize>().map_err(|_| {
+                    anyhow!("Invalid line range '{range}' in code block info '{info}'")
+                })
+            };
+            parsed
+                .highlighted_lines
+                .push(parse_line(start)?..=parse_line(end)?);
+        }
+        rest.replace_range(open..=close, " ");
+    }
+
+    for token in rest.split(|c: char| c.is_whitespace() || c == ',') {
+        match token {
+            "" => {}
+            "linenos" => parsed.line_numbers = Some(true),
+            "nolinenos" => parsed.line_numbers = Some(false),
+            language if parsed.language.is_none() => parsed.language = Some(language.to_string()),
+            _ => {}
+        }
+    }
+    Ok(parsed)
+}
+
+/// Builds the opening tag wrapping one line of code.
+fn open_line(
+    line_number: usize,
+    highlighted: bool,
+    line_numbers: bool,
+    theme: Option<&Theme>,
+) -> String {
+    let mut html = r#"<span class="code-line"#.to_string();
+    if highlighted {
+        html.push_str(" code-line-highlighted");
+    }
+    html.push('"');
+    // With inline styles there's no stylesheet, so the highlight color goes on the line.
+    if let Some(color) = theme
+        .filter(|_| highlighted)
+        .and_then(|theme| theme.settings.line_highlight)
+    {
+        html.push_str(&format!(
+            r#" style="background-color: {}; display: inline-block; width: 100%;""#,
+            css_color(color)
+        ));
+    }
+    html.push('>');
+    if line_numbers {
+        html.push_str(&format!(
+            r#"<span class="code-line-number">{line_number}</span>"#
+        ));
+    }
+    html
+}
+
+/// Returns the opening `<span>` for a syntax scope, matching the classes syntect uses.
+fn scope_span(scope: &Scope) -> String {
+    let classes: Vec<String> = scope
+        .build_string()
+        .split('.')
+        .map(|atom| format!("{CLASS_PREFIX}{atom}"))
+        .collect();
+    format!(r#"<span class="{}">"#, classes.join(" "))
+}
+
+/// Removes the newline ending a highlighted line, which sits just before any
+/// closing `</span>`s, so the line's wrapper can end the line instead.
+fn strip_line_ending(mut html: String) -> String {
+    if let Some(newline) = html.rfind('\n') {
+        html.remove(newline);
+    }
+    html
+}
+
+/// Highlights the contents of a fenced code block with the info string `info`.
+fn highlight_code_block(code: &str, info: &str, config: &HighlightConfig) -> Result<String> {
+    let info = parse_info(info)?;
+    let syntaxes = syntax_set();
+    let syntax = info
+        .language
+        .as_deref()
+        .and_then(|language| syntaxes.find_syntax_by_token(language))
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    let theme = theme(&config.theme)?;
+    let line_numbers = info.line_numbers.unwrap_or(config.line_numbers);
+
+    let mut lines_html = String::new();
+    let mut inline_highlighter = HighlightLines::new(syntax, theme);
+    let mut parse_state = ParseState::new(syntax);
+    let mut scope_stack = ScopeStack::new();
+
+    for (index, line) in LinesWithEndings::from(code).enumerate() {
+        let line_number = index + 1;
+        let highlighted = info
+            .highlighted_lines
+            .iter()
+            .any(|range| range.contains(&line_number));
+        lines_html.push_str(&open_line(
+            line_number,
+            highlighted,
+            line_numbers,
+            config.inline_styles.then_some(theme),
+        ));
+
+        if config.inline_styles {
+            let regions = inline_highlighter.highlight_line(line, syntaxes)?;
+            let html = styled_line_to_highlighted_html(&regions, IncludeBackground::No)?;
+            lines_html.push_str(&strip_line_ending(html));
+        } else {
+            // Scopes can span lines, but each line is wrapped in its own
+            // `<span>`, so reopen the scopes still open from the previous line
+            // and close everything at the end of this one.
+            for scope in scope_stack.as_slice() {
+                lines_html.push_str(&scope_span(scope));
+            }
+            let ops = parse_state.parse_line(line, syntaxes)?;
+            let (html, _) =
+                line_tokens_to_classed_spans(line, &ops, CLASS_STYLE, &mut scope_stack)?;
+            lines_html.push_str(&strip_line_ending(html));
+            lines_html.push_str(&"</span>".repeat(scope_stack.len()));
+        }
+        lines_html.push_str("</span>\n");
+    }
+
+    let pre_style = if config.inline_styles {
+        let mut style = String::new();
+        if let Some(background) = theme.settings.background {
+            style.push_str(&format!("background-color: {};", css_color(background)));
+        }
+        if let Some(foreground) = theme.settings.foreground {
+            style.push_str(&format!(" color: {};", css_color(foreground)));
+        }
+        format!(r#" style="{}""#, style.trim())
+    } else {
+        String::new()
+    };
+
+    let mut html = r#"<div class="code-block""#.to_string();
+    if let Some(language) = &info.language {
+        let language = tera::escape_html(language);
+        html.push_str(&format!(
+            r#" data-lang="{language}"><span class="code-lang">{language}</span>"#
+        ));
+    } else {
+        html.push('>');
+    }
+    html.push_str(&format!(
+        r#"<pre class="{CLASS_PREFIX}code"{pre_style}><code>{lines_html}</code></pre></div>"#
+    ));
+    Ok(html)
+}
+
+/// Replaces every fenced code block in `events` with highlighted HTML.
+/// Indented code blocks have no language, so they're left alone.
+pub(crate) fn highlight_code_blocks<'a>(
+    events: Vec<Event<'a>>,
+    config: &HighlightConfig,
+) -> Result<Vec<Event<'a>>> {
+    let mut transformed = Vec::with_capacity(events.len());
+    let mut events = events.into_iter();
+    while let Some(event) = events.next() {
+        let Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) = event else {
+            transformed.push(event);
+            continue;
+        };
+
+        let mut code = String::new();
+        for inner in events.by_ref() {
+            match inner {
+                Event::End(TagEnd::CodeBlock) => break,
+                Event::Text(text) => code.push_str(&text),
+                _ => {}
+            }
+        }
+        transformed.push(Event::Html(
+            highlight_code_block(&code, &info, config)?.into(),
+        ));
+    }
+    Ok(transformed)
+}
@@ -0,0 +1,655 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::Result;
+use pulldown_cmark::{CowStr, Event, LinkType, Tag, TagEnd};
+use serde::Serialize;
+
+use crate::source_map::Source;
+use crate::toc::TocEntry;
+
+/// A link to a page or heading that doesn't exist, with its line in the
+/// Markdown it came from.
+#[derive(Debug)]
+pub(crate) struct LinkError {
+    pub(crate) line: usize,
+    pub(crate) link: String,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "broken link at line {}: {} in `{}`",
+            self.line, self.message, self.link
+        )
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// A page that can be linked to.
+#[derive(Debug)]
+struct PageTarget {
+    title: String,
+    /// Where the page is written, relative to the site root.
+    target_path: PathBuf,
+    /// The IDs of the page's headings.
+    ids: HashSet<String>,
+}
+
+/// A link from a page, before it's resolved: a URL, or the target of a
+/// `[[Page Title]]` wiki link.
+#[derive(Debug)]
+pub(crate) enum RawLink {
+    Url(String),
+    Wiki(String),
+}
+
+/// A page linking to the current one. Exposed to templates in `backlinks`.
+#[derive(Debug, Serialize)]
+pub(crate) struct Backlink {
+    pub(crate) title: String,
+    /// The linking page's URL, relative to the current page.
+    pub(crate) url: String,
+}
+
+/// What the links in some Markdown are relative to.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum LinkBase<'p> {
+    /// The page written to this path, relative to the site root.
+    Page(&'p Path),
+    /// The site root, served from this base path, like `/blog/`. Links are
+    /// written from the root, like `/blog/about.html`, so they work on any
+    /// page. Summaries use this, since they're shown on other pages.
+    Root(&'p str),
+}
+
+impl LinkBase<'_> {
+    /// Returns the URL of the page written to `target`.
+    pub(crate) fn page_url(&self, target: &Path, pretty_urls: bool) -> String {
+        match self {
+            LinkBase::Page(from) => page_url(from, target, pretty_urls),
+            LinkBase::Root(base_path) => match page_url(Path::new(""), target, pretty_urls) {
+                url if url == "./" => base_path.to_string(),
+                url => format!("{base_path}{url}"),
+            },
+        }
+    }
+}
+
+/// Every page on the site with its title and heading IDs, for resolving links
+/// between pages, and which pages link to each other.
+#[derive(Debug, Default)]
+pub(crate) struct LinkTargets {
+    /// Pages by Markdown file, relative to `pages/`.
+    pages: HashMap<PathBuf, PageTarget>,
+    /// The pages linking to each page.
+    backlinks: HashMap<PathBuf, BTreeSet<PathBuf>>,
+    /// Whether pages are linked as directories. See `page_url`.
+    pretty_urls: bool,
+}
+
+impl LinkTargets {
+    pub(crate) fn new(pretty_urls: bool) -> Self {
+        LinkTargets {
+            pretty_urls,
+            ..LinkTargets::default()
+        }
+    }
+
+    /// Adds the page at `markdown_path`, relative to `pages/`, which is written
+    /// to `target_path`, with the headings in `toc`.
+    pub(crate) fn insert(
+        &mut self,
+        markdown_path: PathBuf,
+        target_path: PathBuf,
+        title: String,
+        toc: &[TocEntry],
+    ) {
+        fn collect_ids(entries: &[TocEntry], ids: &mut HashSet<String>) {
+            for entry in entries {
+                ids.insert(entry.id.clone());
+                collect_ids(&entry.children, ids);
+            }
+        }
+
+        let mut ids = HashSet::new();
+        collect_ids(toc, &mut ids);
+        self.pages.insert(
+            markdown_path,
+            PageTarget {
+                title,
+                target_path,
+                ids,
+            },
+        );
+    }
+
+    /// Records which pages each page links to, once every page has been
+    /// inserted. Broken links are skipped here; they're reported when the
+    /// linking page is rendered.
+    pub(crate) fn add_backlinks(&mut self, links: &[(PathBuf, Vec<RawLink>)]) {
+        for (source, raw_links) in links {
+            for raw_link in raw_links {
+                let resolved = match raw_link {
+                    RawLink::Url(url) => match self.resolve_path(url, source) {
+                        Some(Ok((target, _))) => target,
+                        _ => continue,
+                    },
+                    RawLink::Wiki(target) => {
+                        let title = target.split_once('#').map_or(target.as_str(), |(t, _)| t);
+                        match self.page_by_title(title) {
+                            Ok(target) => target.clone(),
+                            Err(_) => continue,
+                        }
+                    }
+                };
+                if &resolved != source {
+                    self.backlinks
+                        .entry(resolved)
+                        .or_default()
+                        .insert(source.clone());
+                }
+            }
+        }
+    }
+
+    /// Returns the pages linking to the page at `markdown_path`, sorted by
+    /// title, with URLs relative to `from`, where the page is written.
+    pub(crate) fn backlinks(&self, markdown_path: &Path, from: &Path) -> Vec<Backlink> {
+        let mut backlinks: Vec<Backlink> = self
+            .backlinks
+            .get(markdown_path)
+            .into_iter()
+            .flatten()
+            .map(|source| Backlink {
+                title: self.pages[source].title.clone(),
+                url: page_url(from, &self.pages[source].target_path, self.pretty_urls),
+            })
+            .collect();
+        backlinks.sort_by(|a, b| a.title.cmp(&b.title));
+        backlinks
+    }
+
+    /// Finds the page titled `title`, ignoring case if there's no exact match.
+    fn page_by_title(&self, title: &str) -> std::result::Result<&PathBuf, String> {
+        let title = title.trim();
+        let mut matches: Vec<&PathBuf> = self
+            .pages
+            .iter()
+            .filter(|(_, page)| page.title == title)
+            .map(|(path, _)| path)
+            .collect();
+        if matches.is_empty() {
+            matches = self
+                .pages
+                .iter()
+                .filter(|(_, page)| page.title.to_lowercase() == title.to_lowercase())
+                .map(|(path, _)| path)
+                .collect();
+        }
+        matches.sort();
+
+        match matches.as_slice() {
+            [] => Err(format!("there's no page titled `{title}`")),
+            [page] => Ok(page),
+            pages => Err(format!(
+                "more than one page is titled `{title}`: {}",
+                pages
+                    .iter()
+                    .map(|page| format!("`pages/{}`", page.display()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+
+    /// Works out which page a link from `current_page` points to, both
+    /// relative to `pages/`, along with its `#anchor`.
+    ///
+    /// Links to `@/path/to/page.md` are relative to `pages/`, and other links
+    /// to `.md` files are relative to the current page. Other links are
+    /// returned as `None`.
+    fn resolve_path<'l>(
+        &self,
+        link: &'l str,
+        current_page: &Path,
+    ) -> Option<std::result::Result<(PathBuf, Option<&'l str>), String>> {
+        let (path, anchor) = match link.split_once('#') {
+            Some((path, anchor)) => (path, Some(anchor)),
+            None => (link, None),
+        };
+        let current_dir = current_page.parent().unwrap_or(Path::new(""));
+
+        let target = if let Some(from_root) = path.strip_prefix("@/") {
+            normalize(Path::new(from_root))
+        } else if path.ends_with(".md") && !path.contains("://") && !path.starts_with('/') {
+            normalize(&current_dir.join(path))
+        } else {
+            return None;
+        };
+        let Some(target) = target else {
+            return Some(Err("the path leaves the `pages` directory".to_string()));
+        };
+        if !self.pages.contains_key(&target) {
+            return Some(Err(format!(
+                "there's no page at `pages/{}`",
+                target.display()
+            )));
+        }
+        Some(Ok((target, anchor)))
+    }
+
+    /// Returns the URL of `target` from `base`, checking that `anchor` is one
+    /// of its headings.
+    fn url(
+        &self,
+        base: LinkBase,
+        target: &Path,
+        anchor: Option<&str>,
+    ) -> std::result::Result<String, String> {
+        let mut url = base.page_url(&self.pages[target].target_path, self.pretty_urls);
+        if let Some(anchor) = anchor {
+            if !anchor.is_empty() && !self.pages[target].ids.contains(anchor) {
+                return Err(format!(
+                    "there's no heading `#{anchor}` in `pages/{}`",
+                    target.display()
+                ));
+            }
+            url.push('#');
+            url.push_str(anchor);
+        }
+        Ok(url)
+    }
+}
+
+/// Resolves `.`, `..` and empty components, or returns `None` if the path
+/// leaves the directory it's relative to.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// Returns the URL of `target` relative to the directory `from`, where both
+/// are relative to the site root.
+pub(crate) fn relative_url(from: &Path, target: &Path) -> String {
+    let from: Vec<_> = from.components().collect();
+    let target: Vec<_> = target.components().collect();
+    let common = from.iter().zip(&target).take_while(|(a, b)| a == b).count();
+
+    let mut parts = vec![".."; from.len() - common];
+    parts.extend(
+        target[common..]
+            .iter()
+            .filter_map(|c| c.as_os_str().to_str()),
+    );
+    parts.join("/")
+}
+
+/// Returns the URL of the page written to `target` from the page written to
+/// `from`, both relative to the site root. With `pretty_urls`, a page written
+/// to an `index.html` is linked as its directory, like `../about/`.
+pub(crate) fn page_url(from: &Path, target: &Path, pretty_urls: bool) -> String {
+    let url = relative_url(from.parent().unwrap_or(Path::new("")), target);
+    match url.strip_suffix("index.html") {
+        Some("") if pretty_urls => "./".to_string(),
+        Some(dir) if pretty_urls && dir.ends_with('/') => dir.to_string(),
+        _ => url,
+    }
+}
+
+/// Works out the URL for a Markdown link from the page at `current_page`,
+/// relative to `base`, or returns `None` if it's not a link to another page's
+/// Markdown file or a heading on the current page.
+fn resolve(
+    link: &str,
+    current_page: &Path,
+    base: LinkBase,
+    targets: &LinkTargets,
+) -> Option<std::result::Result<String, String>> {
+    if let Some(anchor) = link.strip_prefix('#') {
+        let page = targets.pages.get(current_page)?;
+        if !anchor.is_empty() && !page.ids.contains(anchor) {
+            return Some(Err(format!("there's no heading `#{anchor}` on this page")));
+        }
+        return Some(match base {
+            LinkBase::Page(_) => Ok(link.to_string()),
+            LinkBase::Root(_) => targets.url(base, current_page, Some(anchor)),
+        });
+    }
+    let resolved = targets.resolve_path(link, current_page)?;
+    Some(resolved.and_then(|(target, anchor)| targets.url(base, &target, anchor)))
+}
+
+/// Rewrites `url`, a link or image on the page at `current_page` that's
+/// relative to where the page is written, like `photo.jpg`, to start from
+/// the site root when `base` is `LinkBase::Root`. Other URLs are returned
+/// as they are.
+fn rebase<'a>(
+    url: CowStr<'a>,
+    current_page: &Path,
+    base: LinkBase,
+    targets: &LinkTargets,
+) -> CowStr<'a> {
+    let (LinkBase::Root(base_path), Some(page)) = (base, targets.pages.get(current_page)) else {
+        return url;
+    };
+    let scheme_end = url.find(':');
+    let path_end = url.find(['/', '?', '#']);
+    if url.is_empty()
+        || url.starts_with(['/', '?', '#'])
+        || scheme_end.is_some_and(|colon| path_end.is_none_or(|end| colon < end))
+    {
+        return url;
+    }
+    let (path, rest) = url.split_at(url.find(['?', '#']).unwrap_or(url.len()));
+    let dir = page.target_path.parent().unwrap_or(Path::new(""));
+    match normalize(&dir.join(path)) {
+        Some(from_root) => {
+            let from_root = relative_url(Path::new(""), &from_root);
+            let slash = if path.ends_with('/') && !from_root.is_empty() {
+                "/"
+            } else {
+                ""
+            };
+            CowStr::from(format!("{base_path}{from_root}{slash}{rest}"))
+        }
+        None => url,
+    }
+}
+
+/// A `[[Page Title]]`, `[[Page Title|label]]` or `[[Page Title#heading]]`
+/// wiki link found in some text.
+struct WikiLink<'t> {
+    /// Where the link is in the text, including the brackets.
+    range: Range<usize>,
+    /// The linked page's title, with any `#heading`.
+    target: &'t str,
+    label: &'t str,
+}
+
+/// A run of text, which the parser splits at brackets, gathered to find the
+/// wiki links in it.
+struct TextRun {
+    text: String,
+    /// The run's byte range in the Markdown.
+    range: Range<usize>,
+    /// Where `[`s written as `\[` are in `text`. They don't open wiki links.
+    escaped: Vec<usize>,
+}
+
+/// Gathers the run of text starting with `text` from `events`, which come
+/// with their byte ranges in `source`.
+fn text_run<'a>(
+    text: &str,
+    range: Range<usize>,
+    events: &mut Peekable<impl Iterator<Item = (Event<'a>, Range<usize>)>>,
+    source: &str,
+) -> TextRun {
+    let mut run = TextRun {
+        text: String::new(),
+        range: range.clone(),
+        escaped: vec![],
+    };
+    let push = |run: &mut TextRun, text: &str, range: Range<usize>| {
+        // An escaped bracket is its own event, after an odd number of backslashes.
+        let backslashes = source[..range.start]
+            .bytes()
+            .rev()
+            .take_while(|&b| b == b'\\')
+            .count();
+        if text == "[" && backslashes % 2 == 1 {
+            run.escaped.push(run.text.len());
+        }
+        run.text.push_str(text);
+        run.range.end = range.end;
+    };
+    push(&mut run, text, range);
+    while let Some((Event::Text(next), next_range)) = events.peek() {
+        let (next, next_range) = (next.to_string(), next_range.clone());
+        push(&mut run, &next, next_range);
+        events.next();
+    }
+    run
+}
+
+/// Finds the wiki links in `run`. Macros like `[[ListPosts]]` aren't links,
+/// and neither is `\[[Page Title]]`.
+fn wiki_links(run: &TextRun) -> Vec<WikiLink<'_>> {
+    let text = &run.text;
+    let mut links = vec![];
+    let mut offset = 0;
+    while let Some(open) = text[offset..].find("[[") {
+        let start = offset + open;
+        let Some(close) = text[start + 2..].find("]]") else {
+            break;
+        };
+        let end = start + 2 + close + 2;
+        let inner = &text[start + 2..end - 2];
+        offset = end;
+
+        if inner.trim().is_empty()
+            || inner.contains('[')
+            || inner.split_whitespace().next() == Some("ListPosts")
+            || run.escaped.contains(&start)
+            || run.escaped.contains(&(start + 1))
+        {
+            continue;
+        }
+        let (target, label) = inner.split_once('|').unwrap_or_else(|| {
+            // Without a label, show the title, not the `#heading`.
+            (
+                inner,
+                inner.split_once('#').map_or(inner, |(title, _)| title),
+            )
+        });
+        links.push(WikiLink {
+            range: start..end,
+            target: target.trim(),
+            label: label.trim(),
+        });
+    }
+    links
+}
+
+/// Collects the links in a page's events, which come with their byte ranges
+/// in `source`, to find its backlinks before it's rendered.
+pub(crate) fn raw_links(events: &[(Event, Range<usize>)], source: &str) -> Vec<RawLink> {
+    let mut links = vec![];
+    let mut in_code_block = false;
+    let mut events = events.iter().cloned().peekable();
+    while let Some((event, range)) = events.next() {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+            Event::End(TagEnd::CodeBlock) => in_code_block = false,
+            Event::Start(Tag::Link { dest_url, .. }) => {
+                links.push(RawLink::Url(dest_url.to_string()));
+            }
+            Event::Text(text) if !in_code_block => {
+                let run = text_run(&text, range, &mut events, source);
+                links.extend(
+                    wiki_links(&run)
+                        .into_iter()
+                        .map(|link| RawLink::Wiki(link.target.to_string())),
+                );
+            }
+            _ => {}
+        }
+    }
+    links
+}
+
+/// Replaces the wiki links in a run of text, which starts on `line`, with
+/// links relative to `base`.
+fn expand_wiki_links<'a>(
+    run: &TextRun,
+    line: usize,
+    in_table_cell: bool,
+    base: LinkBase,
+    targets: &LinkTargets,
+    transformed: &mut Vec<(Event<'a>, Range<usize>)>,
+) -> Result<()> {
+    let (text, range) = (&run.text, &run.range);
+    let mut rest = 0;
+    for link in wiki_links(run) {
+        let error = |message| LinkError {
+            line,
+            link: text[link.range.clone()].to_string(),
+            message,
+        };
+        let (title, anchor) = match link.target.split_once('#') {
+            Some((title, anchor)) => (title, Some(anchor)),
+            None => (link.target, None),
+        };
+        let target = targets.page_by_title(title).map_err(error)?;
+        let url = targets.url(base, target, anchor).map_err(error)?;
+
+        if link.range.start > rest {
+            let before = text[rest..link.range.start].to_string();
+            transformed.push((Event::Text(before.into()), range.clone()));
+        }
+        transformed.push((
+            Event::Start(Tag::Link {
+                link_type: LinkType::Inline,
+                dest_url: url.into(),
+                title: "".into(),
+                id: "".into(),
+            }),
+            range.clone(),
+        ));
+        transformed.push((Event::Text(link.label.to_string().into()), range.clone()));
+        transformed.push((Event::End(TagEnd::Link), range.clone()));
+        rest = link.range.end;
+    }
+    // A `|` ends a table cell, so `[[Page|label]]` in a table is split across
+    // two cells, leaving a wiki link that's never closed.
+    if in_table_cell {
+        if let Some(open) = text[rest..].rfind("[[") {
+            let start = rest + open;
+            if !text[start..].contains("]]")
+                && !run.escaped.contains(&start)
+                && !run.escaped.contains(&(start + 1))
+            {
+                return Err(anyhow::Error::new(LinkError {
+                    line,
+                    link: text[start..].to_string(),
+                    message: "a `|` in a table ends the cell, so write it as `\\|`, \
+                              like `[[Page Title\\|label]]`"
+                        .to_string(),
+                }));
+            }
+        }
+    }
+    if rest < text.len() {
+        transformed.push((Event::Text(text[rest..].to_string().into()), range.clone()));
+    }
+    Ok(())
+}
+
+/// Rewrites links to other pages' Markdown files into links to their HTML,
+/// and `[[Page Title]]` wiki links into links to the page with that title,
+/// failing on links to pages or headings that don't exist. `current_page` is
+/// the linking page, relative to `pages/`, and links are relative to `base`;
+/// with `LinkBase::Root`, other relative links and images are rewritten to
+/// start from the root too. Each event comes
+/// with its byte range in `markdown`, the Markdown it was parsed from, which
+/// was rewritten from `source`.
+pub(crate) fn resolve_links<'a>(
+    events: impl Iterator<Item = (Event<'a>, Range<usize>)>,
+    markdown: &str,
+    source: &Source,
+    current_page: &Path,
+    base: LinkBase,
+    targets: &LinkTargets,
+) -> Result<Vec<(Event<'a>, Range<usize>)>> {
+    let line_of = |offset: usize| source.position(offset).0;
+    let mut transformed = vec![];
+    let mut in_code_block = false;
+    let mut in_table_cell = false;
+    let mut events = events.peekable();
+    while let Some((event, range)) = events.next() {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+            Event::End(TagEnd::CodeBlock) => in_code_block = false,
+            Event::Start(Tag::TableCell) => in_table_cell = true,
+            Event::End(TagEnd::TableCell) => in_table_cell = false,
+            Event::Text(text) if !in_code_block => {
+                let run = text_run(&text, range, &mut events, markdown);
+                expand_wiki_links(
+                    &run,
+                    line_of(run.range.start),
+                    in_table_cell,
+                    base,
+                    targets,
+                    &mut transformed,
+                )?;
+                continue;
+            }
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => {
+                let dest_url = match resolve(&dest_url, current_page, base, targets) {
+                    None => rebase(dest_url, current_page, base, targets),
+                    Some(Ok(url)) => CowStr::from(url),
+                    Some(Err(message)) => {
+                        return Err(anyhow::Error::new(LinkError {
+                            line: line_of(range.start),
+                            link: dest_url.to_string(),
+                            message,
+                        }))
+                    }
+                };
+                transformed.push((
+                    Event::Start(Tag::Link {
+                        link_type,
+                        dest_url,
+                        title,
+                        id,
+                    }),
+                    range,
+                ));
+                continue;
+            }
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => {
+                transformed.push((
+                    Event::Start(Tag::Image {
+                        link_type,
+                        dest_url: rebase(dest_url, current_page, base, targets),
+                        title,
+                        id,
+                    }),
+                    range,
+                ));
+                continue;
+            }
+            _ => {}
+        }
+        transformed.push((event, range));
+    }
+    Ok(transformed)
+}
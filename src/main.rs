@@ -1,15 +1,35 @@
 use std::fs::File;
 use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::thread;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 use yaml_rust::{Yaml, YamlLoader};
 
+mod admonitions;
+mod check;
+mod current_page;
+mod data;
+mod external_links;
+mod filters;
+mod front_matter;
 mod gen_site;
+mod highlight;
+mod html;
+mod links;
+mod math;
+mod paginator;
 mod serve;
+mod shortcodes;
+mod site_functions;
+mod source_map;
+mod toc;
 mod watcher;
 
 use clap::{Parser, Subcommand};
+use notify::EventKind;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -26,54 +46,414 @@ enum Commands {
         title: Option<String>,
     },
     Generate,
-    #[command(name = "serve", alias = "serve")]
+    /// Build the site into a temporary directory and check it for problems.
+    Check {
+        /// Also check that links to other sites work.
+        #[arg(long)]
+        external: bool,
+    },
+    #[command(name = "serve")]
     Serve {
         #[arg(long)]
         watch: bool,
     },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 struct Post {
     title: String,
     date: String,
     path: String,
+    /// The URL of the post's generated page, relative to the page listing it.
+    url: String,
+    /// Where the post's page is written, relative to the site root.
+    target_path: PathBuf,
+    tags: Vec<String>,
+    /// The rendered summary of the post's page, filled in during generation.
+    summary: Option<String>,
+}
+
+/// Number of words used for a summary when a page has no `<!-- more -->` marker.
+const DEFAULT_SUMMARY_WORDS: usize = 50;
+
+/// Settings from the `markdown` block of `PUSHPIN.yaml`, which pages can
+/// override with a `markdown` block in their front matter.
+///
+/// Each flag turns on a Markdown extension. The defaults match GitHub
+/// Flavored Markdown.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MarkdownConfig {
+    tables: bool,
+    footnotes: bool,
+    strikethrough: bool,
+    tasklists: bool,
+    /// Turns straight quotes into curly quotes, `--` into en dashes, and so on.
+    smart_punctuation: bool,
+    /// Lets headings set their own ID and classes with `{#id .class}`.
+    heading_attributes: bool,
+    definition_lists: bool,
+    /// Renders `$...$`, `$$...$$` and ```` ```math ```` blocks to MathML.
+    math: bool,
+    /// Whether headings get a `#` permalink to themselves.
+    heading_anchors: bool,
+}
+
+impl Default for MarkdownConfig {
+    fn default() -> Self {
+        MarkdownConfig {
+            tables: true,
+            footnotes: true,
+            strikethrough: true,
+            tasklists: true,
+            smart_punctuation: false,
+            heading_attributes: true,
+            definition_lists: false,
+            math: false,
+            heading_anchors: false,
+        }
+    }
+}
+
+/// Applies the `markdown` block of `PUSHPIN.yaml` to the defaults. Like a
+/// page's `markdown` front matter, unknown settings are an error, so typos
+/// don't go unnoticed.
+fn parse_markdown_config(node: &Yaml) -> Result<MarkdownConfig> {
+    let mut markdown = serde_json::to_value(MarkdownConfig::default())?;
+    match data::yaml_to_json(node) {
+        serde_json::Value::Null => {}
+        serde_json::Value::Object(overrides) => {
+            if let serde_json::Value::Object(settings) = &mut markdown {
+                settings.extend(overrides);
+            }
+        }
+        _ => {
+            return Err(anyhow!(
+                "`markdown` in PUSHPIN.yaml must be settings, like `math: true`"
+            ))
+        }
+    }
+    serde_json::from_value(markdown).map_err(|e| anyhow!("Invalid `markdown` in PUSHPIN.yaml: {e}"))
+}
+
+/// Settings from the `highlight` block of `PUSHPIN.yaml`, for build-time
+/// syntax highlighting of fenced code blocks.
+#[derive(Debug, Clone)]
+struct HighlightConfig {
+    enabled: bool,
+    /// The name of one of syntect's bundled themes.
+    theme: String,
+    /// Whether to style code with inline `style` attributes rather than classes.
+    inline_styles: bool,
+    line_numbers: bool,
+    /// Where the theme's stylesheet is written when using classes, relative to the site root.
+    css_path: String,
+}
+
+fn parse_highlight_config(node: &Yaml) -> HighlightConfig {
+    HighlightConfig {
+        enabled: node["enabled"].as_bool().unwrap_or(false),
+        theme: node["theme"]
+            .as_str()
+            .unwrap_or("InspiredGitHub")
+            .to_string(),
+        inline_styles: node["style"].as_str() == Some("inline"),
+        line_numbers: node["line_numbers"].as_bool().unwrap_or(false),
+        css_path: node["css"].as_str().unwrap_or("highlight.css").to_string(),
+    }
+}
+
+/// Settings from the `link_check` block of `PUSHPIN.yaml`, for
+/// `pushpin check --external`.
+#[derive(Debug, Clone)]
+struct LinkCheckConfig {
+    /// How many links are checked at once.
+    concurrency: usize,
+    /// The minimum time between two requests to the same host, in milliseconds.
+    host_delay_ms: u64,
+    /// How long to wait for a response, in seconds.
+    timeout_secs: u64,
+    /// Where results are cached between runs, relative to the site root.
+    cache_path: String,
+    /// How long a cached result is used before the link is checked again, in hours.
+    cache_ttl_hours: u64,
+}
+
+fn parse_link_check_config(node: &Yaml) -> LinkCheckConfig {
+    let number = |key: &str, default: u64| {
+        node[key]
+            .as_i64()
+            .map(|n| n.max(0) as u64)
+            .unwrap_or(default)
+    };
+    LinkCheckConfig {
+        concurrency: number("concurrency", 8).max(1) as usize,
+        host_delay_ms: number("host_delay_ms", 500),
+        timeout_secs: number("timeout", 10),
+        cache_path: node["cache"]
+            .as_str()
+            .unwrap_or(".pushpin-link-cache.json")
+            .to_string(),
+        cache_ttl_hours: number("cache_ttl_hours", 24),
+    }
+}
+
+/// An entry of the `cascade` block of `PUSHPIN.yaml`: front matter values for
+/// every page whose Markdown file, relative to `pages/`, matches `pattern`.
+#[derive(Debug, Clone)]
+struct CascadeRule {
+    pattern: globset::GlobMatcher,
+    values: serde_json::Map<String, serde_json::Value>,
+}
+
+fn parse_cascade(node: &Yaml) -> Result<Vec<CascadeRule>> {
+    let Some(entries) = node.as_hash() else {
+        return Ok(vec![]);
+    };
+    let mut rules = vec![];
+    for (pattern, values) in entries {
+        let pattern = pattern
+            .as_str()
+            .ok_or_else(|| anyhow!("Each `cascade` key must be a path glob, like `guides/**`"))?;
+        let serde_json::Value::Object(values) = data::yaml_to_json(values) else {
+            return Err(anyhow!(
+                "The `cascade` values for `{pattern}` must be front matter keys and values"
+            ));
+        };
+        // `*` stays within a directory, and `**` crosses them.
+        let pattern = globset::GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| anyhow!("Invalid `cascade` glob `{pattern}`: {e}"))?
+            .compile_matcher();
+        rules.push(CascadeRule { pattern, values });
+    }
+    Ok(rules)
 }
 
 #[derive(Debug, Clone)]
 struct Config {
     posts: Vec<Post>,
+    summary_words: usize,
+    markdown: MarkdownConfig,
+    highlight: HighlightConfig,
+    link_check: LinkCheckConfig,
+    /// The `site` block, available to templates as `site`.
+    site: serde_json::Value,
+    /// The template for pages with no `template` in their front matter or section.
+    default_template: Option<String>,
+    /// Front matter from the `cascade` block, applied in order.
+    cascade: Vec<CascadeRule>,
+    /// Front matter fields rendered from Markdown to HTML before templates see them.
+    markdown_fields: Vec<String>,
+    /// Like `markdown_fields`, but a single paragraph isn't wrapped in `<p>`.
+    inline_markdown_fields: Vec<String>,
+    /// Every page and its headings, for resolving links. Filled in by `generate`.
+    link_targets: Arc<links::LinkTargets>,
+    /// Where the site is written. Empty for the site root.
+    output_dir: PathBuf,
+    /// Whether pages are written to an `index.html` of their own, like
+    /// `about/index.html` for `about.md`, and linked as its directory, like
+    /// `about/`.
+    pretty_urls: bool,
+    /// Where the site is served from, like `/blog/`. Starts and ends with `/`.
+    base_path: String,
 }
 
-fn parse_config(yaml_doc: &Yaml) -> Config {
+fn parse_config(yaml_doc: &Yaml) -> Result<Config> {
     let mut posts = Vec::new();
     let posts_node = &yaml_doc["posts"];
     for post in posts_node.as_vec().unwrap() {
         let title = post["title"].as_str().unwrap().to_string();
         let date = post["date"].as_str().unwrap().to_string();
         let path = post["path"].as_str().unwrap().to_string();
-        posts.push(Post { title, date, path });
+        let url = path.replace(".md", ".html");
+        let tags = post["tags"]
+            .as_vec()
+            .map(|tags| {
+                tags.iter()
+                    .filter_map(|tag| tag.as_str().map(|s| s.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        posts.push(Post {
+            title,
+            date,
+            path,
+            target_path: PathBuf::from(&url),
+            url,
+            tags,
+            summary: None,
+        });
     }
-    Config { posts }
+    let summary_words = match &yaml_doc["summary_words"] {
+        Yaml::BadValue => DEFAULT_SUMMARY_WORDS,
+        Yaml::Integer(n) if *n >= 0 => *n as usize,
+        _ => {
+            return Err(anyhow!(
+                "`summary_words` must be a whole number of words, like `50`"
+            ))
+        }
+    };
+    let markdown = parse_markdown_config(&yaml_doc["markdown"])?;
+    let highlight = parse_highlight_config(&yaml_doc["highlight"]);
+    let link_check = parse_link_check_config(&yaml_doc["link_check"]);
+    let strings = |node: &Yaml| -> Vec<String> {
+        node.as_vec()
+            .map(|items| {
+                items
+                    .iter()
+                    .filter_map(|item| item.as_str().map(|s| s.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let default_template = yaml_doc["default_template"].as_str().map(|s| s.to_string());
+    let cascade = parse_cascade(&yaml_doc["cascade"])?;
+    let markdown_fields = strings(&yaml_doc["markdown_fields"]);
+    let inline_markdown_fields = strings(&yaml_doc["inline_markdown_fields"]);
+    let pretty_urls = match &yaml_doc["pretty_urls"] {
+        Yaml::BadValue => false,
+        Yaml::Boolean(pretty_urls) => *pretty_urls,
+        _ => return Err(anyhow!("`pretty_urls` must be `true` or `false`")),
+    };
+    let base_path = match &yaml_doc["base_path"] {
+        Yaml::BadValue => "/".to_string(),
+        Yaml::String(path) => match path.trim_matches('/') {
+            "" => "/".to_string(),
+            path => format!("/{path}/"),
+        },
+        _ => return Err(anyhow!("`base_path` must be a path, like `/blog/`")),
+    };
+    let site = match data::yaml_to_json(&yaml_doc["site"]) {
+        serde_json::Value::Null => serde_json::Value::Object(Default::default()),
+        site => site,
+    };
+    Ok(Config {
+        posts,
+        summary_words,
+        markdown,
+        highlight,
+        link_check,
+        site,
+        default_template,
+        cascade,
+        markdown_fields,
+        inline_markdown_fields,
+        link_targets: Arc::default(),
+        output_dir: PathBuf::new(),
+        pretty_urls,
+        base_path,
+    })
 }
 
-fn gen() -> Result<()> {
+fn load_config() -> Result<Config> {
     let mut config_file = File::open("PUSHPIN.yaml")?;
     let mut contents = String::new();
     config_file.read_to_string(&mut contents)?;
 
     let yaml_loader = YamlLoader::load_from_str(&contents).unwrap();
     let yaml_doc = &yaml_loader[0];
-    let config = parse_config(yaml_doc);
+    parse_config(yaml_doc)
+}
+
+/// Generates the site, returning the pages with no template, or none if it
+/// couldn't be generated.
+fn gen() -> Vec<PathBuf> {
+    match load_config().and_then(|config| gen_site::generate(&config)) {
+        Ok(generated) => {
+            println!(
+                "📌 success: generated site; created {} files",
+                generated.files
+            );
+            generated.pages_without_template
+        }
+        Err(err) => {
+            println!("Error: {err}");
+            vec![]
+        }
+    }
+}
 
-    match gen_site::generate(&config) {
-        Ok(num_files_generated) => {
-            println!("📌 success: generated site; created {num_files_generated} files")
+/// Warns about each page in `pages`, which have no template, so they're
+/// written as bare HTML fragments. `pushpin check` reports them as problems
+/// instead.
+fn warn_pages_without_template(pages: &[PathBuf]) {
+    for page in pages {
+        eprintln!(
+            "⚠️ warning: '{}' has no template, so it's written as a bare HTML fragment",
+            page.display()
+        );
+    }
+}
+
+/// Builds the site into a temporary directory and reports any problems with
+/// it. Returns whether the site is free of problems.
+fn check(external: bool) -> Result<bool> {
+    let mut config = load_config()?;
+    let output_dir = tempfile::tempdir()?;
+    config.output_dir = output_dir.path().to_path_buf();
+
+    let generated = match gen_site::generate(&config) {
+        Ok(generated) => generated,
+        Err(err) => {
+            println!("Error: {err}");
+            return Ok(false);
         }
-        Err(err) => println!("Error: {err}"),
     };
 
-    Ok(())
+    let num_pages = generated.files;
+    let mut problems: Vec<String> = generated
+        .pages_without_template
+        .into_iter()
+        .map(|page| {
+            format!(
+                "{}: no template, so it's written as a bare HTML fragment",
+                page.display()
+            )
+        })
+        .collect();
+    problems.extend(
+        check::check_site(output_dir.path(), Path::new("."), &config.base_path)?
+            .into_iter()
+            .map(|problem| format!("{}: {}", problem.page.display(), problem.message)),
+    );
+    if external {
+        let urls = check::outbound_urls(output_dir.path())?;
+        problems.extend(
+            external_links::check_external_links(urls, &config.link_check)?
+                .into_iter()
+                .map(|problem| format!("{}: {}", problem.page.display(), problem.message)),
+        );
+    }
+
+    for problem in &problems {
+        println!("  {problem}");
+    }
+    if problems.is_empty() {
+        println!("📌 success: checked {num_pages} pages; found no problems");
+    } else {
+        println!(
+            "😥 checked {num_pages} pages; found {} problems",
+            problems.len()
+        );
+    }
+    Ok(problems.is_empty())
+}
+
+/// Regenerates the site after the files in `event` changed.
+fn regenerate(event: &notify::Event) {
+    for page in &event.paths {
+        println!("🔄 Re-rendering site after update for page: {:?}", page);
+    }
+    let _ = gen();
+}
+
+/// Starts watching `data/`, regenerating the site when it changes. If `data/`
+/// is removed, its watcher sees nothing more, and a new one is started when
+/// it's created again.
+fn watch_data() {
+    watcher::start_file_watcher("data", regenerate, true);
 }
 
 fn main() -> Result<(), std::io::Error> {
@@ -91,39 +471,60 @@ fn main() -> Result<(), std::io::Error> {
             Err(err) => println!("Error: {err}"),
         },
         Commands::Generate => {
-            let _ = gen();
+            warn_pages_without_template(&gen());
         }
+        Commands::Check { external } => match check(*external) {
+            Ok(true) => {}
+            Ok(false) => std::process::exit(1),
+            Err(err) => {
+                println!("Error: {err}");
+                std::process::exit(1);
+            }
+        },
         Commands::Serve { watch } => {
-            let _ = gen();
+            warn_pages_without_template(&gen());
+            let base_path = load_config().map_or_else(|_| "/".to_string(), |c| c.base_path);
 
             if !(*watch) {
                 println!(
-                    "📌 local server available at http://127.0.0.1:7878 (type Ctrl+C to stop)"
+                    "📌 local server available at http://127.0.0.1:7878{base_path} (type Ctrl+C to stop)"
                 );
-                serve::serve();
+                serve::serve(&base_path);
                 return Ok(());
             }
 
-            println!("📌 local server available at http://127.0.0.1:7878");
-            let server_handle = thread::spawn(|| {
-                serve::serve();
+            println!("📌 local server available at http://127.0.0.1:7878{base_path}");
+            let server_handle = thread::spawn(move || {
+                serve::serve(&base_path);
             });
 
-            println!("🔍 Watching for changes in 'pages/', 'templates/', (type Ctrl+C to stop):");
-            let pages_watcher_handle = watcher::start_file_watcher(
-                "pages",
-                |_| {
-                    let _ = gen();
-                },
-                true,
-            );
-            let watcher_handle = watcher::start_file_watcher(
-                "templates",
-                |_| {
-                    let _ = gen();
+            println!("🔍 Watching for changes in 'pages/', 'templates/', 'data/' and 'PUSHPIN.yaml' (type Ctrl+C to stop):");
+            let pages_watcher_handle = watcher::start_file_watcher("pages", regenerate, true);
+            let watcher_handle = watcher::start_file_watcher("templates", regenerate, true);
+
+            // `data/` is optional, so it's watched from when it exists. The site
+            // root is watched too, but not recursively, since the site is
+            // generated into it, to start watching `data/` when it's created.
+            if Path::new("data").is_dir() {
+                watch_data();
+            }
+            let root_watcher_handle = watcher::start_file_watcher(
+                ".",
+                |event| {
+                    let created_data = matches!(event.kind, EventKind::Create(_))
+                        && event
+                            .paths
+                            .iter()
+                            .any(|path| path.file_name() == Some("data".as_ref()) && path.is_dir());
+                    if created_data {
+                        watch_data();
+                        regenerate(event);
+                    }
                 },
-                true,
+                false,
             );
+            let config_watcher_handle =
+                watcher::start_file_watcher("PUSHPIN.yaml", regenerate, false);
 
             if let Err(e) = pages_watcher_handle.join() {
                 eprintln!("😥 internal error: {:?}. Please file a bug at https://github.com/tedbauer/pushpin/issues.", e);
@@ -133,6 +534,12 @@ fn main() -> Result<(), std::io::Error> {
                 eprintln!("😥 internal error: {:?}. Please file a bug at https://github.com/tedbauer/pushpin/issues.", e);
             }
 
+            for handle in [root_watcher_handle, config_watcher_handle] {
+                if let Err(e) = handle.join() {
+                    eprintln!("😥 internal error: {:?}. Please file a bug at https://github.com/tedbauer/pushpin/issues.", e);
+                }
+            }
+
             // Join the server handle
             if let Err(e) = server_handle.join() {
                 eprintln!("😥 internal error: {:?}. Please file a bug at https://github.com/tedbauer/pushpin/issues.", e);
@@ -0,0 +1,1016 @@
+use std::fmt;
+use std::ops::Range;
+
+use anyhow::{anyhow, Result};
+use pulldown_cmark::{CodeBlockKind, Event, Tag, TagEnd};
+
+use crate::source_map::Source;
+
+/// An expression that couldn't be converted to MathML, with its position in
+/// the Markdown it came from.
+#[derive(Debug)]
+pub(crate) struct MathError {
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    pub(crate) latex: String,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for MathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid math at line {}, column {}: {} in `{}`",
+            self.line, self.column, self.message, self.latex
+        )
+    }
+}
+
+impl std::error::Error for MathError {}
+
+/// Converts `latex`, at byte `offset` of the Markdown that was parsed, to
+/// MathML, or reports where in `source` it went wrong.
+fn convert(latex: &str, display: bool, source: &Source, offset: usize) -> Result<String> {
+    latex_to_mathml(latex, display).map_err(|err| {
+        let (line, column) = source.position(offset);
+        anyhow::Error::new(MathError {
+            line,
+            column,
+            latex: latex.trim().to_string(),
+            message: err.to_string(),
+        })
+    })
+}
+
+/// Replaces the math in a page's events with MathML: inline `$...$`, display
+/// `$$...$$`, and fenced ```` ```math ```` blocks. Each event comes with its
+/// byte range in the Markdown it was parsed from, which was rewritten from
+/// `source`.
+pub(crate) fn render_math<'a>(
+    mut events: impl Iterator<Item = (Event<'a>, Range<usize>)>,
+    source: &Source,
+) -> Result<Vec<Event<'a>>> {
+    let mut transformed = vec![];
+    while let Some((event, range)) = events.next() {
+        match event {
+            Event::InlineMath(latex) => transformed.push(Event::InlineHtml(
+                convert(&latex, false, source, range.start)?.into(),
+            )),
+            Event::DisplayMath(latex) => transformed.push(Event::InlineHtml(
+                convert(&latex, true, source, range.start)?.into(),
+            )),
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info)))
+                if info.split_whitespace().next() == Some("math") =>
+            {
+                let mut latex = String::new();
+                for (inner, _) in events.by_ref() {
+                    match inner {
+                        Event::End(TagEnd::CodeBlock) => break,
+                        Event::Text(text) => latex.push_str(&text),
+                        _ => {}
+                    }
+                }
+                transformed.push(Event::Html(
+                    convert(&latex, true, source, range.start)?.into(),
+                ));
+            }
+            event => transformed.push(event),
+        }
+    }
+    Ok(transformed)
+}
+
+/// Converts a LaTeX math expression to MathML.
+///
+/// This covers the parts of LaTeX that come up in technical writing: scripts,
+/// fractions, roots, Greek letters and common symbols, functions like `\sin`,
+/// font commands like `\mathbf`, accents, `\left`/`\right` delimiters and
+/// matrix-like environments. Anything else is an error, rather than output
+/// that silently renders wrong.
+pub(crate) fn latex_to_mathml(latex: &str, display: bool) -> Result<String> {
+    let mut parser = MathParser {
+        tokens: tokenize(latex)?,
+        position: 0,
+        display,
+    };
+    let nodes = parser.parse_sequence(&[])?;
+    if let Some(token) = parser.peek() {
+        return Err(anyhow!("unexpected '{}'", token.text()));
+    }
+
+    let display_attribute = if display { r#" display="block""# } else { "" };
+    Ok(format!(
+        r#"<math xmlns="http://www.w3.org/1998/Math/MathML"{display_attribute}><semantics><mrow>{}</mrow><annotation encoding="application/x-tex">{}</annotation></semantics></math>"#,
+        render_all(&nodes),
+        escape(latex.trim())
+    ))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A command like `\frac`, without the backslash.
+    Command(String),
+    Letter(char),
+    Number(String),
+    /// Any other single character, like `+`, `(` or `{`.
+    Symbol(char),
+    /// The braced argument of a text command like `\text`, kept verbatim
+    /// since whitespace matters there.
+    RawText(String),
+}
+
+/// Commands whose argument is text rather than math.
+const TEXT_COMMANDS: [&str; 5] = ["text", "textrm", "textit", "textbf", "mbox"];
+
+impl Token {
+    fn text(&self) -> String {
+        match self {
+            Token::Command(name) => format!("\\{name}"),
+            Token::Letter(c) | Token::Symbol(c) => c.to_string(),
+            Token::Number(n) | Token::RawText(n) => n.clone(),
+        }
+    }
+}
+
+fn tokenize(latex: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = latex.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '\\' => {
+                let mut name = String::new();
+                while let Some(&next) = chars.peek() {
+                    if !next.is_ascii_alphabetic() {
+                        break;
+                    }
+                    name.push(next);
+                    chars.next();
+                }
+                // Commands like `\,` and `\\` are a backslash and one symbol.
+                if name.is_empty() {
+                    let symbol = chars
+                        .next()
+                        .ok_or(anyhow!("expression ends with a lone '\\'"))?;
+                    name.push(symbol);
+                }
+                if TEXT_COMMANDS.contains(&name.as_str()) {
+                    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                    if chars.next() != Some('{') {
+                        return Err(anyhow!("expected '{{' after '\\{name}'"));
+                    }
+                    let mut text = String::new();
+                    let mut depth = 0;
+                    loop {
+                        match chars.next() {
+                            None => return Err(anyhow!("unclosed '{{' after '\\{name}'")),
+                            Some('}') if depth == 0 => break,
+                            Some(c) => {
+                                match c {
+                                    '{' => depth += 1,
+                                    '}' => depth -= 1,
+                                    _ => {}
+                                }
+                                text.push(c);
+                            }
+                        }
+                    }
+                    tokens.push(Token::Command(name));
+                    tokens.push(Token::RawText(text));
+                    continue;
+                }
+                tokens.push(Token::Command(name));
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut number = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if !(next.is_ascii_digit() || next == '.') {
+                        break;
+                    }
+                    number.push(next);
+                    chars.next();
+                }
+                tokens.push(Token::Number(number));
+            }
+            c if c.is_alphabetic() => tokens.push(Token::Letter(c)),
+            c => tokens.push(Token::Symbol(c)),
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug)]
+enum Node {
+    Identifier(String),
+    /// An identifier that is upright rather than italic, like `sin`.
+    Function(String),
+    Number(String),
+    Operator(String),
+    Text(String),
+    Space(&'static str),
+    Row(Vec<Node>),
+    Fraction(Box<Node>, Box<Node>),
+    Binomial(Box<Node>, Box<Node>),
+    Sqrt(Box<Node>),
+    Root(Box<Node>, Box<Node>),
+    Scripts {
+        base: Box<Node>,
+        sub: Option<Box<Node>>,
+        sup: Option<Box<Node>>,
+        /// Whether scripts go above and below the base, as for `\sum`.
+        limits: bool,
+    },
+    Accent(Box<Node>, &'static str),
+    Under(Box<Node>, &'static str),
+    Styled(Box<Node>, &'static str),
+    Delimited(String, Vec<Node>, String),
+    Table(Vec<Vec<Vec<Node>>>),
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render_all(nodes: &[Node]) -> String {
+    nodes.iter().map(render).collect()
+}
+
+fn render(node: &Node) -> String {
+    match node {
+        Node::Identifier(name) => format!("<mi>{}</mi>", escape(name)),
+        Node::Function(name) => format!(r#"<mi mathvariant="normal">{}</mi>"#, escape(name)),
+        Node::Number(n) => format!("<mn>{}</mn>", escape(n)),
+        Node::Operator(op) => format!("<mo>{}</mo>", escape(op)),
+        Node::Text(text) => format!("<mtext>{}</mtext>", escape(text)),
+        Node::Space(width) => format!(r#"<mspace width="{width}"/>"#),
+        Node::Row(nodes) => format!("<mrow>{}</mrow>", render_all(nodes)),
+        Node::Fraction(num, den) => format!("<mfrac>{}{}</mfrac>", render(num), render(den)),
+        Node::Binomial(top, bottom) => format!(
+            r#"<mrow><mo>(</mo><mfrac linethickness="0">{}{}</mfrac><mo>)</mo></mrow>"#,
+            render(top),
+            render(bottom)
+        ),
+        Node::Sqrt(radicand) => format!("<msqrt>{}</msqrt>", render(radicand)),
+        Node::Root(radicand, index) => {
+            format!("<mroot>{}{}</mroot>", render(radicand), render(index))
+        }
+        Node::Scripts {
+            base,
+            sub,
+            sup,
+            limits,
+        } => {
+            let (sub_tag, sup_tag, both_tag) = if *limits {
+                ("munder", "mover", "munderover")
+            } else {
+                ("msub", "msup", "msubsup")
+            };
+            match (sub, sup) {
+                (Some(sub), Some(sup)) => format!(
+                    "<{both_tag}>{}{}{}</{both_tag}>",
+                    render(base),
+                    render(sub),
+                    render(sup)
+                ),
+                (Some(sub), None) => {
+                    format!("<{sub_tag}>{}{}</{sub_tag}>", render(base), render(sub))
+                }
+                (None, Some(sup)) => {
+                    format!("<{sup_tag}>{}{}</{sup_tag}>", render(base), render(sup))
+                }
+                (None, None) => render(base),
+            }
+        }
+        Node::Accent(base, accent) => format!(
+            r#"<mover accent="true">{}<mo>{accent}</mo></mover>"#,
+            render(base)
+        ),
+        Node::Under(base, mark) => format!(
+            r#"<munder accentunder="true">{}<mo>{mark}</mo></munder>"#,
+            render(base)
+        ),
+        Node::Styled(inner, variant) => {
+            format!(
+                r#"<mstyle mathvariant="{variant}">{}</mstyle>"#,
+                render(inner)
+            )
+        }
+        Node::Delimited(open, inner, close) => format!(
+            r#"<mrow><mo fence="true" stretchy="true">{}</mo>{}<mo fence="true" stretchy="true">{}</mo></mrow>"#,
+            escape(open),
+            render_all(inner),
+            escape(close)
+        ),
+        Node::Table(rows) => {
+            let rows: String = rows
+                .iter()
+                .map(|row| {
+                    let cells: String = row
+                        .iter()
+                        .map(|cell| format!("<mtd>{}</mtd>", render_all(cell)))
+                        .collect();
+                    format!("<mtr>{cells}</mtr>")
+                })
+                .collect();
+            format!("<mtable>{rows}</mtable>")
+        }
+    }
+}
+
+/// Maps a command to the character it stands for, and whether it's an
+/// operator (`<mo>`) rather than an identifier (`<mi>`).
+fn symbol(command: &str) -> Option<(&'static str, bool)> {
+    let identifier = |s| Some((s, false));
+    let operator = |s| Some((s, true));
+    match command {
+        "alpha" => identifier("α"),
+        "beta" => identifier("β"),
+        "gamma" => identifier("γ"),
+        "delta" => identifier("δ"),
+        "epsilon" => identifier("ϵ"),
+        "varepsilon" => identifier("ε"),
+        "zeta" => identifier("ζ"),
+        "eta" => identifier("η"),
+        "theta" => identifier("θ"),
+        "vartheta" => identifier("ϑ"),
+        "iota" => identifier("ι"),
+        "kappa" => identifier("κ"),
+        "lambda" => identifier("λ"),
+        "mu" => identifier("μ"),
+        "nu" => identifier("ν"),
+        "xi" => identifier("ξ"),
+        "pi" => identifier("π"),
+        "rho" => identifier("ρ"),
+        "sigma" => identifier("σ"),
+        "tau" => identifier("τ"),
+        "upsilon" => identifier("υ"),
+        "phi" => identifier("ϕ"),
+        "varphi" => identifier("φ"),
+        "chi" => identifier("χ"),
+        "psi" => identifier("ψ"),
+        "omega" => identifier("ω"),
+        "Gamma" => identifier("Γ"),
+        "Delta" => identifier("Δ"),
+        "Theta" => identifier("Θ"),
+        "Lambda" => identifier("Λ"),
+        "Xi" => identifier("Ξ"),
+        "Pi" => identifier("Π"),
+        "Sigma" => identifier("Σ"),
+        "Upsilon" => identifier("Υ"),
+        "Phi" => identifier("Φ"),
+        "Psi" => identifier("Ψ"),
+        "Omega" => identifier("Ω"),
+        "infty" => identifier("∞"),
+        "partial" => identifier("∂"),
+        "nabla" => identifier("∇"),
+        "hbar" => identifier("ℏ"),
+        "ell" => identifier("ℓ"),
+        "emptyset" => identifier("∅"),
+        "pm" => operator("±"),
+        "mp" => operator("∓"),
+        "times" => operator("×"),
+        "div" => operator("÷"),
+        "cdot" => operator("⋅"),
+        "ast" => operator("∗"),
+        "circ" => operator("∘"),
+        "le" | "leq" => operator("≤"),
+        "ge" | "geq" => operator("≥"),
+        "ne" | "neq" => operator("≠"),
+        "approx" => operator("≈"),
+        "equiv" => operator("≡"),
+        "sim" => operator("∼"),
+        "simeq" => operator("≃"),
+        "propto" => operator("∝"),
+        "ll" => operator("≪"),
+        "gg" => operator("≫"),
+        "in" => operator("∈"),
+        "notin" => operator("∉"),
+        "ni" => operator("∋"),
+        "subset" => operator("⊂"),
+        "subseteq" => operator("⊆"),
+        "supset" => operator("⊃"),
+        "supseteq" => operator("⊇"),
+        "cup" => operator("∪"),
+        "cap" => operator("∩"),
+        "setminus" => operator("∖"),
+        "land" | "wedge" => operator("∧"),
+        "lor" | "vee" => operator("∨"),
+        "neg" | "lnot" => operator("¬"),
+        "forall" => operator("∀"),
+        "exists" => operator("∃"),
+        "to" | "rightarrow" => operator("→"),
+        "leftarrow" | "gets" => operator("←"),
+        "leftrightarrow" => operator("↔"),
+        "Rightarrow" | "implies" => operator("⇒"),
+        "Leftarrow" => operator("⇐"),
+        "Leftrightarrow" | "iff" => operator("⇔"),
+        "mapsto" => operator("↦"),
+        "mid" => operator("∣"),
+        "parallel" => operator("∥"),
+        "perp" => operator("⊥"),
+        "angle" => operator("∠"),
+        "ldots" | "dots" => operator("…"),
+        "cdots" => operator("⋯"),
+        "vdots" => operator("⋮"),
+        "ddots" => operator("⋱"),
+        "langle" => operator("⟨"),
+        "rangle" => operator("⟩"),
+        "lfloor" => operator("⌊"),
+        "rfloor" => operator("⌋"),
+        "lceil" => operator("⌈"),
+        "rceil" => operator("⌉"),
+        "{" => operator("{"),
+        "}" => operator("}"),
+        "|" => operator("‖"),
+        "%" => operator("%"),
+        "$" => operator("$"),
+        "&" => operator("&"),
+        "#" => operator("#"),
+        "_" => operator("_"),
+        _ => None,
+    }
+}
+
+/// Large operators, whose scripts go above and below in display math.
+fn large_operator(command: &str) -> Option<&'static str> {
+    match command {
+        "sum" => Some("∑"),
+        "prod" => Some("∏"),
+        "coprod" => Some("∐"),
+        "int" => Some("∫"),
+        "iint" => Some("∬"),
+        "iiint" => Some("∭"),
+        "oint" => Some("∮"),
+        "bigcup" => Some("⋃"),
+        "bigcap" => Some("⋂"),
+        _ => None,
+    }
+}
+
+/// Named functions, set upright. The second value is whether scripts go
+/// above and below, like `\lim_{x \to 0}`.
+fn function(command: &str) -> Option<bool> {
+    match command {
+        "sin" | "cos" | "tan" | "cot" | "sec" | "csc" | "arcsin" | "arccos" | "arctan" | "sinh"
+        | "cosh" | "tanh" | "log" | "ln" | "lg" | "exp" | "deg" | "dim" | "ker" | "arg" | "det"
+        | "gcd" | "Pr" => Some(false),
+        "lim" | "liminf" | "limsup" | "max" | "min" | "sup" | "inf" => Some(true),
+        _ => None,
+    }
+}
+
+fn accent(command: &str) -> Option<&'static str> {
+    match command {
+        "hat" | "widehat" => Some("^"),
+        "bar" | "overline" => Some("¯"),
+        "vec" => Some("→"),
+        "tilde" | "widetilde" => Some("~"),
+        "dot" => Some("˙"),
+        "ddot" => Some("¨"),
+        _ => None,
+    }
+}
+
+fn math_variant(command: &str) -> Option<&'static str> {
+    match command {
+        "mathbf" => Some("bold"),
+        "mathit" => Some("italic"),
+        "mathrm" | "operatorname" => Some("normal"),
+        "mathbb" => Some("double-struck"),
+        "mathcal" => Some("script"),
+        "mathfrak" => Some("fraktur"),
+        "mathsf" => Some("sans-serif"),
+        "mathtt" => Some("monospace"),
+        _ => None,
+    }
+}
+
+fn space(command: &str) -> Option<&'static str> {
+    match command {
+        "," => Some("0.1667em"),
+        ":" | ">" => Some("0.2222em"),
+        ";" => Some("0.2778em"),
+        " " => Some("0.25em"),
+        "quad" => Some("1em"),
+        "qquad" => Some("2em"),
+        "!" => Some("-0.1667em"),
+        _ => None,
+    }
+}
+
+struct MathParser {
+    tokens: Vec<Token>,
+    position: usize,
+    /// Whether this is display math, where large operators take their
+    /// scripts above and below rather than beside.
+    display: bool,
+}
+
+impl MathParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect_symbol(&mut self, symbol: char, context: &str) -> Result<()> {
+        match self.next() {
+            Some(Token::Symbol(c)) if c == symbol => Ok(()),
+            Some(token) => Err(anyhow!(
+                "expected '{symbol}' {context}, found '{}'",
+                token.text()
+            )),
+            None => Err(anyhow!("expected '{symbol}' {context}, found the end")),
+        }
+    }
+
+    /// Parses nodes until one of the `terminators` (or the end) is next.
+    fn parse_sequence(&mut self, terminators: &[Token]) -> Result<Vec<Node>> {
+        let mut nodes = vec![];
+        while let Some(token) = self.peek() {
+            if terminators.contains(token) {
+                break;
+            }
+            if let Some(node) = self.parse_scripted()? {
+                nodes.push(node);
+            }
+        }
+        Ok(nodes)
+    }
+
+    /// Parses an atom followed by any `_` and `^` scripts. `\limits` or
+    /// `\nolimits` after an operator puts its scripts above and below it, or
+    /// beside it, whether or not this is display math.
+    fn parse_scripted(&mut self) -> Result<Option<Node>> {
+        let (base, limits) = match self.parse_atom()? {
+            Some(atom) => atom,
+            None => return Ok(None),
+        };
+
+        let mut placement = None;
+        while let Some(Token::Command(name)) = self.peek() {
+            let above_and_below = match name.as_str() {
+                "limits" => true,
+                "nolimits" => false,
+                _ => break,
+            };
+            if !matches!(base, Node::Operator(_) | Node::Function(_)) {
+                return Err(anyhow!("'\\{name}' must follow an operator, like '\\sum'"));
+            }
+            self.next();
+            placement = Some(above_and_below);
+        }
+
+        let mut sub = None;
+        let mut sup = None;
+        loop {
+            match self.peek() {
+                Some(Token::Symbol('_')) if sub.is_none() => {
+                    self.next();
+                    sub = Some(Box::new(self.parse_argument("after '_'")?));
+                }
+                Some(Token::Symbol('^')) if sup.is_none() => {
+                    self.next();
+                    sup = Some(Box::new(self.parse_argument("after '^'")?));
+                }
+                Some(Token::Symbol('\'')) if sup.is_none() => {
+                    self.next();
+                    let mut primes = "′".to_string();
+                    while self.peek() == Some(&Token::Symbol('\'')) {
+                        self.next();
+                        primes.push('′');
+                    }
+                    sup = Some(Box::new(Node::Operator(primes)));
+                }
+                Some(Token::Symbol(c @ ('_' | '^'))) => {
+                    return Err(anyhow!("double '{c}'; use braces to group scripts"))
+                }
+                _ => break,
+            }
+        }
+
+        if sub.is_none() && sup.is_none() {
+            return Ok(Some(base));
+        }
+        Ok(Some(Node::Scripts {
+            base: Box::new(base),
+            sub,
+            sup,
+            limits: placement.unwrap_or(limits && self.display),
+        }))
+    }
+
+    /// Parses a required argument: a `{...}` group or a single atom.
+    fn parse_argument(&mut self, context: &str) -> Result<Node> {
+        match self.peek() {
+            None => Err(anyhow!("missing argument {context}")),
+            Some(Token::Symbol('}')) => Err(anyhow!("missing argument {context}")),
+            Some(Token::Symbol('{')) => {
+                self.next();
+                let nodes = self.parse_sequence(&[Token::Symbol('}')])?;
+                self.expect_symbol('}', "to close '{'")?;
+                Ok(Node::Row(nodes))
+            }
+            _ => match self.parse_atom()? {
+                Some((node, _)) => Ok(node),
+                None => Err(anyhow!("missing argument {context}")),
+            },
+        }
+    }
+
+    /// Parses a `{...}` group as plain text, for environment names.
+    fn parse_raw_group(&mut self, context: &str) -> Result<String> {
+        self.expect_symbol('{', context)?;
+        let mut text = String::new();
+        loop {
+            match self.next() {
+                None => return Err(anyhow!("unclosed '{{' {context}")),
+                Some(Token::Symbol('}')) => break,
+                Some(token) => text.push_str(&token.text()),
+            }
+        }
+        Ok(text)
+    }
+
+    /// Parses a delimiter after `\left` or `\right`, where `.` means none.
+    fn parse_delimiter(&mut self, command: &str) -> Result<String> {
+        match self.next() {
+            Some(Token::Symbol('.')) | Some(Token::Number(_)) => Ok(String::new()),
+            Some(Token::Symbol(c)) => Ok(c.to_string()),
+            Some(Token::Command(name)) => match symbol(&name) {
+                Some((s, _)) => Ok(s.to_string()),
+                None => Err(anyhow!("'\\{name}' is not a delimiter for '\\{command}'")),
+            },
+            Some(token) => Err(anyhow!(
+                "'{}' is not a delimiter for '\\{command}'",
+                token.text()
+            )),
+            None => Err(anyhow!("missing delimiter after '\\{command}'")),
+        }
+    }
+
+    /// Parses the body of `\begin{name}`, up to the matching `\end{name}`.
+    fn parse_environment(&mut self, name: &str) -> Result<Node> {
+        let (open, close) = match name {
+            "matrix" | "aligned" | "align" | "align*" | "array" | "gathered" => ("", ""),
+            "pmatrix" => ("(", ")"),
+            "bmatrix" => ("[", "]"),
+            "Bmatrix" => ("{", "}"),
+            "vmatrix" => ("|", "|"),
+            "Vmatrix" => ("‖", "‖"),
+            "cases" => ("{", ""),
+            _ => return Err(anyhow!("unknown environment '{name}'")),
+        };
+        // `array` takes a column spec, like `{cc}`, which doesn't affect MathML.
+        if name == "array" {
+            self.parse_raw_group("after '\\begin{array}'")?;
+        }
+
+        let mut rows = vec![];
+        let mut row = vec![];
+        let terminators = [
+            Token::Symbol('&'),
+            Token::Command("\\".to_string()),
+            Token::Command("end".to_string()),
+        ];
+        loop {
+            let cell = self.parse_sequence(&terminators)?;
+            row.push(cell);
+            match self.next() {
+                Some(Token::Symbol('&')) => {}
+                Some(Token::Command(c)) if c == "\\" => rows.push(std::mem::take(&mut row)),
+                Some(Token::Command(c)) if c == "end" => {
+                    let end_name = self.parse_raw_group("after '\\end'")?;
+                    if end_name != name {
+                        return Err(anyhow!(
+                            "'\\begin{{{name}}}' is closed by '\\end{{{end_name}}}'"
+                        ));
+                    }
+                    break;
+                }
+                _ => return Err(anyhow!("'\\begin{{{name}}}' is never closed")),
+            }
+        }
+        // Ignore the empty row left by a trailing `\\`.
+        if !(row.len() == 1 && row[0].is_empty()) {
+            rows.push(row);
+        }
+
+        let table = Node::Table(rows);
+        if open.is_empty() && close.is_empty() {
+            Ok(table)
+        } else {
+            Ok(Node::Delimited(
+                open.to_string(),
+                vec![table],
+                close.to_string(),
+            ))
+        }
+    }
+
+    /// Parses a single atom. Returns the node, and whether its scripts go
+    /// above and below it, or `None` for tokens that produce no output.
+    fn parse_atom(&mut self) -> Result<Option<(Node, bool)>> {
+        let Some(token) = self.next() else {
+            return Err(anyhow!("unexpected end of expression"));
+        };
+        let node = match token {
+            Token::Letter(c) => Node::Identifier(c.to_string()),
+            Token::Number(n) => Node::Number(n),
+            Token::Symbol('{') => {
+                let nodes = self.parse_sequence(&[Token::Symbol('}')])?;
+                self.expect_symbol('}', "to close '{'")?;
+                Node::Row(nodes)
+            }
+            Token::Symbol('}') => return Err(anyhow!("unmatched '}}'")),
+            Token::Symbol(c @ ('^' | '_')) => {
+                return Err(anyhow!("'{c}' has nothing to attach to"))
+            }
+            Token::Symbol('&') => return Err(anyhow!("'&' outside of an environment")),
+            Token::Symbol('~') => Node::Space("0.25em"),
+            Token::Symbol(c) => Node::Operator(c.to_string()),
+            Token::Command(name) => return self.parse_command(&name),
+            Token::RawText(text) => Node::Text(text),
+        };
+        Ok(Some((node, false)))
+    }
+
+    fn parse_command(&mut self, name: &str) -> Result<Option<(Node, bool)>> {
+        if let Some((s, is_operator)) = symbol(name) {
+            let node = if is_operator {
+                Node::Operator(s.to_string())
+            } else {
+                Node::Identifier(s.to_string())
+            };
+            return Ok(Some((node, false)));
+        }
+        if let Some(op) = large_operator(name) {
+            return Ok(Some((Node::Operator(op.to_string()), true)));
+        }
+        if let Some(limits) = function(name) {
+            return Ok(Some((Node::Function(name.to_string()), limits)));
+        }
+        if let Some(width) = space(name) {
+            return Ok(Some((Node::Space(width), false)));
+        }
+        if let Some(mark) = accent(name) {
+            let base = self.parse_argument(&format!("for '\\{name}'"))?;
+            return Ok(Some((Node::Accent(Box::new(base), mark), false)));
+        }
+        if let Some(variant) = math_variant(name) {
+            let inner = self.parse_argument(&format!("for '\\{name}'"))?;
+            return Ok(Some((Node::Styled(Box::new(inner), variant), false)));
+        }
+
+        let node = match name {
+            "frac" | "dfrac" | "tfrac" => {
+                let num = self.parse_argument(&format!("for the numerator of '\\{name}'"))?;
+                let den = self.parse_argument(&format!("for the denominator of '\\{name}'"))?;
+                Node::Fraction(Box::new(num), Box::new(den))
+            }
+            "binom" => {
+                let top = self.parse_argument("for '\\binom'")?;
+                let bottom = self.parse_argument("for '\\binom'")?;
+                Node::Binomial(Box::new(top), Box::new(bottom))
+            }
+            "sqrt" => {
+                if self.peek() == Some(&Token::Symbol('[')) {
+                    self.next();
+                    let index = self.parse_sequence(&[Token::Symbol(']')])?;
+                    self.expect_symbol(']', "to close the index of '\\sqrt'")?;
+                    let radicand = self.parse_argument("for '\\sqrt'")?;
+                    Node::Root(Box::new(radicand), Box::new(Node::Row(index)))
+                } else {
+                    Node::Sqrt(Box::new(self.parse_argument("for '\\sqrt'")?))
+                }
+            }
+            "underline" => {
+                let base = self.parse_argument("for '\\underline'")?;
+                Node::Under(Box::new(base), "_")
+            }
+            "text" | "textrm" | "textit" | "textbf" | "mbox" => match self.next() {
+                Some(Token::RawText(text)) => Node::Text(text),
+                _ => return Err(anyhow!("missing argument for '\\{name}'")),
+            },
+            "left" => {
+                let open = self.parse_delimiter("left")?;
+                let inner = self.parse_sequence(&[Token::Command("right".to_string())])?;
+                if self.next().is_none() {
+                    return Err(anyhow!("'\\left{open}' is never closed by '\\right'"));
+                }
+                let close = self.parse_delimiter("right")?;
+                Node::Delimited(open, inner, close)
+            }
+            "right" => return Err(anyhow!("'\\right' without a matching '\\left'")),
+            "begin" => {
+                let environment = self.parse_raw_group("after '\\begin'")?;
+                self.parse_environment(&environment)?
+            }
+            "end" => return Err(anyhow!("'\\end' without a matching '\\begin'")),
+            "\\" => return Err(anyhow!("'\\\\' outside of an environment")),
+            // Sizing commands only affect delimiters, which MathML sizes itself.
+            "big" | "Big" | "bigg" | "Bigg" | "displaystyle" | "textstyle" => return Ok(None),
+            "limits" | "nolimits" => {
+                return Err(anyhow!("'\\{name}' must follow an operator, like '\\sum'"))
+            }
+            _ => return Err(anyhow!("unknown command '\\{name}'")),
+        };
+        Ok(Some((node, false)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the MathML for `latex`, without the `<math>` element around it.
+    fn convert_inner(latex: &str, display: bool) -> String {
+        let mathml = latex_to_mathml(latex, display).unwrap();
+        let start = mathml.find("<semantics><mrow>").unwrap() + "<semantics><mrow>".len();
+        let end = mathml.rfind("</mrow><annotation").unwrap();
+        mathml[start..end].to_string()
+    }
+
+    #[test]
+    fn converts_latex() {
+        let under_over = "<munderover><mo>∑</mo><mrow><mi>i</mi><mo>=</mo><mn>1</mn></mrow><mi>n</mi></munderover>";
+        let beside =
+            "<msubsup><mo>∑</mo><mrow><mi>i</mi><mo>=</mo><mn>1</mn></mrow><mi>n</mi></msubsup>";
+        let fence =
+            |delimiter: &str| format!(r#"<mo fence="true" stretchy="true">{delimiter}</mo>"#);
+        let cases = [
+            // Scripts.
+            ("x_1", false, "<msub><mi>x</mi><mn>1</mn></msub>".to_string()),
+            ("x^2", false, "<msup><mi>x</mi><mn>2</mn></msup>".to_string()),
+            (
+                "x_i^2",
+                false,
+                "<msubsup><mi>x</mi><mi>i</mi><mn>2</mn></msubsup>".to_string(),
+            ),
+            (
+                "x^{2n}",
+                false,
+                "<msup><mi>x</mi><mrow><mn>2</mn><mi>n</mi></mrow></msup>".to_string(),
+            ),
+            ("f''", false, "<msup><mi>f</mi><mo>′′</mo></msup>".to_string()),
+            // Large operators and limits.
+            (r"\sum_{i=1}^n", false, beside.to_string()),
+            (r"\sum_{i=1}^n", true, under_over.to_string()),
+            (r"\sum\limits_{i=1}^n", false, under_over.to_string()),
+            (r"\sum\nolimits_{i=1}^n", true, beside.to_string()),
+            (
+                r"\int\nolimits_0^1",
+                true,
+                "<msubsup><mo>∫</mo><mn>0</mn><mn>1</mn></msubsup>".to_string(),
+            ),
+            (
+                r"\lim_{x \to 0}",
+                true,
+                r#"<munder><mi mathvariant="normal">lim</mi><mrow><mi>x</mi><mo>→</mo><mn>0</mn></mrow></munder>"#.to_string(),
+            ),
+            // Fractions and roots.
+            (
+                r"\frac{a}{b}",
+                false,
+                "<mfrac><mrow><mi>a</mi></mrow><mrow><mi>b</mi></mrow></mfrac>".to_string(),
+            ),
+            (r"\frac a b", false, "<mfrac><mi>a</mi><mi>b</mi></mfrac>".to_string()),
+            (
+                r"\sqrt[3]{x}",
+                false,
+                "<mroot><mrow><mi>x</mi></mrow><mrow><mn>3</mn></mrow></mroot>".to_string(),
+            ),
+            // Symbols, text and fonts.
+            (
+                r"\alpha \le b",
+                false,
+                "<mi>α</mi><mo>≤</mo><mi>b</mi>".to_string(),
+            ),
+            (
+                "a < b",
+                false,
+                "<mi>a</mi><mo>&lt;</mo><mi>b</mi>".to_string(),
+            ),
+            (
+                r"\text{if } x",
+                false,
+                "<mtext>if </mtext><mi>x</mi>".to_string(),
+            ),
+            (
+                r"\mathbf{v}",
+                false,
+                r#"<mstyle mathvariant="bold"><mrow><mi>v</mi></mrow></mstyle>"#.to_string(),
+            ),
+            // Delimiters.
+            (
+                r"\left( \frac{a}{b} \right)",
+                false,
+                format!(
+                    "<mrow>{}<mfrac><mrow><mi>a</mi></mrow><mrow><mi>b</mi></mrow></mfrac>{}</mrow>",
+                    fence("("),
+                    fence(")")
+                ),
+            ),
+            (
+                r"\left. x \right|",
+                false,
+                format!("<mrow>{}<mi>x</mi>{}</mrow>", fence(""), fence("|")),
+            ),
+            // Environments.
+            (
+                r"\begin{pmatrix} a & b \\ c & d \end{pmatrix}",
+                false,
+                format!(
+                    "<mrow>{}<mtable><mtr><mtd><mi>a</mi></mtd><mtd><mi>b</mi></mtd></mtr><mtr><mtd><mi>c</mi></mtd><mtd><mi>d</mi></mtd></mtr></mtable>{}</mrow>",
+                    fence("("),
+                    fence(")")
+                ),
+            ),
+            (
+                r"\begin{matrix} a \\ \end{matrix}",
+                false,
+                "<mtable><mtr><mtd><mi>a</mi></mtd></mtr></mtable>".to_string(),
+            ),
+            (
+                r"\begin{cases} 1 & x > 0 \\ 0 & \text{otherwise} \end{cases}",
+                false,
+                format!(
+                    "<mrow>{}<mtable><mtr><mtd><mn>1</mn></mtd><mtd><mi>x</mi><mo>&gt;</mo><mn>0</mn></mtd></mtr><mtr><mtd><mn>0</mn></mtd><mtd><mtext>otherwise</mtext></mtd></mtr></mtable>{}</mrow>",
+                    fence("{"),
+                    fence("")
+                ),
+            ),
+        ];
+        for (latex, display, expected) in cases {
+            assert_eq!(convert_inner(latex, display), expected, "for `{latex}`");
+        }
+    }
+
+    #[test]
+    fn wraps_mathml_with_the_source() {
+        let inline = latex_to_mathml("a<b", false).unwrap();
+        assert!(
+            inline.starts_with(r#"<math xmlns="http://www.w3.org/1998/Math/MathML"><semantics>"#)
+        );
+        assert!(inline.ends_with(
+            r#"<annotation encoding="application/x-tex">a&lt;b</annotation></semantics></math>"#
+        ));
+        let display = latex_to_mathml("x", true).unwrap();
+        assert!(display
+            .starts_with(r#"<math xmlns="http://www.w3.org/1998/Math/MathML" display="block">"#));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let cases = [
+            ("x^", "missing argument after '^'"),
+            ("x^2^3", "double '^'; use braces to group scripts"),
+            ("_x", "'_' has nothing to attach to"),
+            ("{x", "expected '}' to close '{', found the end"),
+            ("x}", "unmatched '}'"),
+            ("a & b", "'&' outside of an environment"),
+            (r"x \\ y", r"'\\' outside of an environment"),
+            (
+                r"\frac{a}",
+                r"missing argument for the denominator of '\frac'",
+            ),
+            (r"\foo", r"unknown command '\foo'"),
+            (r"\text x", r"expected '{' after '\text'"),
+            (r"x\", r"expression ends with a lone '\'"),
+            (
+                r"x\limits_0",
+                r"'\limits' must follow an operator, like '\sum'",
+            ),
+            (
+                r"\nolimits",
+                r"'\nolimits' must follow an operator, like '\sum'",
+            ),
+            (r"\left( x", r"'\left(' is never closed by '\right'"),
+            (r"x \right)", r"'\right' without a matching '\left'"),
+            (
+                r"\left\foo x \right)",
+                r"'\foo' is not a delimiter for '\left'",
+            ),
+            (r"\begin{foo} x \end{foo}", "unknown environment 'foo'"),
+            (r"\begin{matrix} a & b", r"'\begin{matrix}' is never closed"),
+            (
+                r"\begin{matrix} a \end{pmatrix}",
+                r"'\begin{matrix}' is closed by '\end{pmatrix}'",
+            ),
+            (r"\end{matrix}", r"'\end' without a matching '\begin'"),
+        ];
+        for (latex, expected) in cases {
+            match latex_to_mathml(latex, false) {
+                Ok(mathml) => panic!("`{latex}` converted to {mathml}"),
+                Err(err) => assert_eq!(err.to_string(), expected, "for `{latex}`"),
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::links;
+
+/// One page of a paginated collection. Exposed to templates as `paginator`.
+///
+/// `first`, `last`, `previous` and `next` are URLs relative to this page, so
+/// templates can link to them with `{{ paginator.next }}`.
+#[derive(Debug, Serialize)]
+pub(crate) struct Paginator {
+    /// The items on this page of the collection. Items that are pages, and the
+    /// pages of a taxonomy term, get a `url` relative to this page.
+    pub(crate) items: Vec<Value>,
+    pub(crate) paginate_by: usize,
+    /// The 1-based index of this page.
+    pub(crate) current_index: usize,
+    pub(crate) total_pages: usize,
+    pub(crate) total_items: usize,
+    pub(crate) first: String,
+    pub(crate) last: String,
+    pub(crate) previous: Option<String>,
+    pub(crate) next: Option<String>,
+    /// Where this page of the collection is written.
+    pub(crate) target_path: PathBuf,
+}
+
+/// Returns the output path of the `index`th (1-based) page of a collection
+/// whose first page is written to `first_target`.
+///
+/// The first page keeps its normal path; later pages go under `page/<n>/`,
+/// next to the first page. For example, `blog/index.html` paginates into
+/// `blog/page/2/index.html`, and `archive.html` into `archive/page/2/index.html`.
+fn pager_path(first_target: &Path, index: usize) -> PathBuf {
+    if index <= 1 {
+        return first_target.to_path_buf();
+    }
+
+    let parent = first_target.parent().unwrap_or(Path::new(""));
+    let base = match first_target.file_stem().and_then(|s| s.to_str()) {
+        Some("index") | None => parent.to_path_buf(),
+        Some(stem) => parent.join(stem),
+    };
+    base.join("page").join(index.to_string()).join("index.html")
+}
+
+/// Adds a `url` from the page written to `from` to `item` if it's a page,
+/// with a `target_path`, and to each of its `pages` if it's a taxonomy term.
+fn add_urls(item: &mut Value, from: &Path, pretty_urls: bool) {
+    let Value::Object(fields) = item else {
+        return;
+    };
+    if let Some(target_path) = fields.get("target_path").and_then(|v| v.as_str()) {
+        let url = links::page_url(from, Path::new(target_path), pretty_urls);
+        fields.insert("url".to_string(), Value::String(url));
+    }
+    if let Some(Value::Array(pages)) = fields.get_mut("pages") {
+        for page in pages {
+            add_urls(page, from, pretty_urls);
+        }
+    }
+}
+
+/// Splits `items` into pages of `paginate_by` items each, the first written
+/// to `first_target`. URLs are written like other links between pages; see
+/// `links::page_url`.
+///
+/// An empty collection still produces a single (empty) page, so the page
+/// declaring the pagination is always rendered.
+pub(crate) fn paginate(
+    items: Vec<Value>,
+    paginate_by: usize,
+    first_target: &Path,
+    pretty_urls: bool,
+) -> Vec<Paginator> {
+    let paginate_by = paginate_by.max(1);
+    let total_items = items.len();
+    let total_pages = total_items.div_ceil(paginate_by).max(1);
+
+    let mut chunks: Vec<Vec<Value>> = items.chunks(paginate_by).map(|c| c.to_vec()).collect();
+    if chunks.is_empty() {
+        chunks.push(vec![]);
+    }
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, mut items)| {
+            let current_index = i + 1;
+            let target_path = pager_path(first_target, current_index);
+            let url = |index| {
+                links::page_url(&target_path, &pager_path(first_target, index), pretty_urls)
+            };
+            for item in &mut items {
+                add_urls(item, &target_path, pretty_urls);
+            }
+            Paginator {
+                items,
+                paginate_by,
+                current_index,
+                total_pages,
+                total_items,
+                first: url(1),
+                last: url(total_pages),
+                previous: (current_index > 1).then(|| url(current_index - 1)),
+                next: (current_index < total_pages).then(|| url(current_index + 1)),
+                target_path: target_path.clone(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Returns the `previous`, `first`, `last` and `next` URLs of `pager`.
+    fn urls(pager: &Paginator) -> [Option<&str>; 4] {
+        [
+            pager.previous.as_deref(),
+            Some(&pager.first),
+            Some(&pager.last),
+            pager.next.as_deref(),
+        ]
+    }
+
+    #[test]
+    fn writes_later_pages_under_page() {
+        assert_eq!(
+            pager_path(Path::new("blog/index.html"), 1),
+            Path::new("blog/index.html")
+        );
+        assert_eq!(
+            pager_path(Path::new("blog/index.html"), 2),
+            Path::new("blog/page/2/index.html")
+        );
+        assert_eq!(
+            pager_path(Path::new("archive.html"), 3),
+            Path::new("archive/page/3/index.html")
+        );
+        assert_eq!(
+            pager_path(Path::new("index.html"), 2),
+            Path::new("page/2/index.html")
+        );
+    }
+
+    #[test]
+    fn splits_items_into_pages() {
+        let items = (1..=5).map(|n| json!(n)).collect();
+        let pagers = paginate(items, 2, Path::new("blog/index.html"), false);
+        let pages: Vec<_> = pagers
+            .iter()
+            .map(|pager| (pager.current_index, pager.items.clone()))
+            .collect();
+        assert_eq!(
+            pages,
+            [
+                (1, vec![json!(1), json!(2)]),
+                (2, vec![json!(3), json!(4)]),
+                (3, vec![json!(5)])
+            ]
+        );
+        assert!(pagers
+            .iter()
+            .all(|pager| pager.total_pages == 3 && pager.total_items == 5));
+        assert_eq!(pagers[2].target_path, Path::new("blog/page/3/index.html"));
+
+        let pagers = paginate(vec![], 0, Path::new("blog/index.html"), false);
+        assert_eq!(pagers.len(), 1);
+        assert!(pagers[0].items.is_empty());
+        assert_eq!(
+            urls(&pagers[0]),
+            [None, Some("index.html"), Some("index.html"), None]
+        );
+    }
+
+    #[test]
+    fn links_pages_relative_to_each_other() {
+        let items = (1..=3).map(|n| json!(n)).collect();
+        let pagers = paginate(items, 1, Path::new("blog/index.html"), false);
+        assert_eq!(
+            urls(&pagers[0]),
+            [
+                None,
+                Some("index.html"),
+                Some("page/3/index.html"),
+                Some("page/2/index.html")
+            ]
+        );
+        assert_eq!(
+            urls(&pagers[1]),
+            [
+                Some("../../index.html"),
+                Some("../../index.html"),
+                Some("../3/index.html"),
+                Some("../3/index.html")
+            ]
+        );
+
+        let items = (1..=2).map(|n| json!(n)).collect();
+        let pagers = paginate(items, 1, Path::new("blog/index.html"), true);
+        assert_eq!(
+            urls(&pagers[1]),
+            [Some("../../"), Some("../../"), Some("./"), None]
+        );
+    }
+
+    #[test]
+    fn gives_pages_a_url() {
+        let items = vec![
+            json!({"title": "A", "target_path": "blog/a.html"}),
+            json!({"name": "rust", "pages": [{"title": "B", "target_path": "b/index.html"}]}),
+            json!("not a page"),
+        ];
+        let pagers = paginate(items, 3, Path::new("blog/page.html"), true);
+        assert_eq!(pagers[0].items[0]["url"], "a.html");
+        assert_eq!(pagers[0].items[1]["url"], json!(null));
+        assert_eq!(pagers[0].items[1]["pages"][0]["url"], "../b/");
+        assert_eq!(pagers[0].items[2], "not a page");
+    }
+}
@@ -4,37 +4,55 @@ use std::net::TcpListener;
 use std::net::TcpStream;
 use std::path::Path;
 
-pub(crate) fn serve() {
+/// Serves the site from the current directory. `base_path`, like `/blog/`, is
+/// where the site's root is served.
+pub(crate) fn serve(base_path: &str) {
     let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
 
     for stream in listener.incoming() {
         let stream = stream.unwrap();
 
-        handle_connection(stream);
+        handle_connection(stream, base_path);
     }
 }
 
-fn handle_connection(mut stream: TcpStream) {
+fn handle_connection(mut stream: TcpStream, base_path: &str) {
     let mut buffer = [0; 1024];
-    stream.read(&mut buffer).unwrap();
+    let bytes_read = stream.read(&mut buffer).unwrap();
 
-    let req_line = String::from_utf8_lossy(&buffer);
+    let req_line = String::from_utf8_lossy(&buffer[..bytes_read]);
 
-    if req_line.starts_with("GET / HTTP/1.1") {
-        serve_file(&mut stream, "index.html", "text/html; charset=utf-8");
-    } else if req_line.starts_with("GET /") {
-        // Extract the requested path
-        let start_index = req_line.find('/').unwrap() + 1;
-        let end_index = req_line[start_index..]
-            .find(' ')
-            .unwrap_or(req_line.len() - start_index)
-            + start_index;
-        let requested_path = &req_line[start_index..end_index];
-
-        if requested_path.is_empty() {
-            // This case should ideally not be reached if the first condition works
+    if let Some(target) = req_line
+        .strip_prefix("GET ")
+        .and_then(|rest| rest.split(' ').next())
+    {
+        let target = target.split(['?', '#']).next().unwrap_or("");
+        // Everything is under `base_path`, and its root, like `/` or `/blog`,
+        // goes to it.
+        let Some(requested_path) = target.strip_prefix(base_path) else {
+            let response = if target == "/" || format!("{target}/") == base_path {
+                format!("HTTP/1.1 301 MOVED PERMANENTLY\r\nLocation: {base_path}\r\n\r\n")
+            } else {
+                format!("HTTP/1.1 404 NOT FOUND\r\n\r\n<h1>404 Not Found: {target}</h1>")
+            };
+            stream.write_all(response.as_bytes()).unwrap();
+            stream.flush().unwrap();
             return;
-        }
+        };
+        let requested_path = if requested_path.is_empty() {
+            "index.html"
+        } else {
+            requested_path
+        };
+
+        // Directory URLs like `/page/2/` are served from their `index.html`.
+        let index_path = Path::new(requested_path).join("index.html");
+        let requested_path = if Path::new(requested_path).is_dir() && index_path.is_file() {
+            index_path.to_str().unwrap_or(requested_path).to_string()
+        } else {
+            requested_path.to_string()
+        };
+        let requested_path = requested_path.as_str();
 
         let path = Path::new(requested_path);
         if path.exists() {
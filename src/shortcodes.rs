@@ -0,0 +1,632 @@
+use std::fmt;
+use std::ops::Range;
+
+use anyhow::Result;
+use pulldown_cmark::{CowStr, Event, Tag, TagEnd};
+use serde_json::{Map, Number, Value};
+
+use crate::source_map::SourceMap;
+
+/// A shortcode invocation found in a page's Markdown, like
+/// `{{< youtube id="abc" >}}` or `{{< note >}}Some *text*{{< /note >}}`.
+#[derive(Debug)]
+pub(crate) struct Shortcode {
+    pub(crate) name: String,
+    pub(crate) args: Map<String, Value>,
+    /// The raw Markdown between the opening and closing tags, for the block form.
+    pub(crate) body: Option<String>,
+    /// Where the body starts in the Markdown the shortcode was found in.
+    pub(crate) body_start: usize,
+}
+
+/// A shortcode that couldn't be parsed, with its line in the Markdown it
+/// came from.
+#[derive(Debug)]
+pub(crate) struct ShortcodeError {
+    pub(crate) line: usize,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for ShortcodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid shortcode at line {}: {}",
+            self.line, self.message
+        )
+    }
+}
+
+impl std::error::Error for ShortcodeError {}
+
+/// Returns a `ShortcodeError` for `message` on `line`.
+fn error(line: usize, message: impl Into<String>) -> anyhow::Error {
+    anyhow::Error::new(ShortcodeError {
+        line,
+        message: message.into(),
+    })
+}
+
+/// An opening or closing tag, like `{{< name arg="x" >}}` or `{{< /name >}}`.
+struct ShortcodeTag {
+    name: String,
+    args: Map<String, Value>,
+    closing: bool,
+    /// The byte range of the whole tag in the source.
+    span: Range<usize>,
+}
+
+/// How a placeholder starts. See `placeholder`.
+const PLACEHOLDER_PREFIX: &str = "pushpin-shortcode-";
+
+/// Returns the text that replaces the `index`th shortcode until it's rendered.
+/// It's plain text so the Markdown parser leaves it alone.
+fn placeholder(index: usize) -> String {
+    format!("{PLACEHOLDER_PREFIX}{index}-end")
+}
+
+/// Finds the first placeholder in `text` for one of `count` shortcodes, and
+/// returns the text before it, its index and the text after it.
+fn next_placeholder(text: &str, count: usize) -> Option<(&str, usize, &str)> {
+    let mut search_from = 0;
+    while let Some(found) = text[search_from..].find(PLACEHOLDER_PREFIX) {
+        let start = search_from + found;
+        let digits_start = start + PLACEHOLDER_PREFIX.len();
+        let digits_end = digits_start
+            + text[digits_start..]
+                .bytes()
+                .take_while(u8::is_ascii_digit)
+                .count();
+        let index = text[digits_start..digits_end].parse::<usize>().ok();
+        if let (Some(index), Some(after)) = (index, text[digits_end..].strip_prefix("-end")) {
+            if index < count {
+                return Some((&text[..start], index, after));
+            }
+        }
+        search_from = digits_start;
+    }
+    None
+}
+
+/// Returns the 1-based line number of the byte `offset` in `source`.
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+/// Returns the byte ranges of fenced code blocks and inline code spans, where
+/// shortcodes are left as-is.
+fn code_ranges(markdown: &str) -> Vec<Range<usize>> {
+    let mut ranges = vec![];
+    let mut open_fence: Option<(usize, &str)> = None;
+    let mut offset = 0;
+    for line in markdown.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let indent = line.len() - trimmed.len();
+        let fence = ["```", "~~~"]
+            .into_iter()
+            .find(|fence| indent < 4 && trimmed.starts_with(fence));
+        match (open_fence, fence) {
+            (None, Some(fence)) => open_fence = Some((offset, fence)),
+            (Some((start, open)), Some(fence)) if open == fence => {
+                ranges.push(start..offset + line.len());
+                open_fence = None;
+            }
+            _ => {}
+        }
+        offset += line.len();
+    }
+    if let Some((start, _)) = open_fence {
+        ranges.push(start..markdown.len());
+    }
+
+    // An inline code span runs from a run of backticks to the next run of the
+    // same length.
+    let bytes = markdown.as_bytes();
+    let backtick_run = |from: usize| bytes[from..].iter().take_while(|&&b| b == b'`').count();
+    let mut i = 0;
+    while i < bytes.len() {
+        if let Some(fenced) = ranges.iter().find(|range| range.contains(&i)) {
+            i = fenced.end;
+            continue;
+        }
+        if bytes[i] != b'`' {
+            i += 1;
+            continue;
+        }
+
+        let run = backtick_run(i);
+        let mut j = i + run;
+        let mut end = None;
+        while j < bytes.len() {
+            if bytes[j] == b'`' {
+                let closing_run = backtick_run(j);
+                if closing_run == run {
+                    end = Some(j + closing_run);
+                    break;
+                }
+                j += closing_run;
+            } else {
+                j += 1;
+            }
+        }
+        match end {
+            Some(end) => {
+                ranges.push(i..end);
+                i = end;
+            }
+            None => i += run,
+        }
+    }
+    ranges
+}
+
+/// Parses a single argument value: quoted strings, booleans, numbers, or a bare word.
+fn parse_value(raw: &str) -> Value {
+    if let Ok(b) = raw.parse::<bool>() {
+        return Value::Bool(b);
+    }
+    if let Ok(i) = raw.parse::<i64>() {
+        return Value::Number(i.into());
+    }
+    if let Some(n) = raw.parse::<f64>().ok().and_then(Number::from_f64) {
+        return Value::Number(n);
+    }
+    Value::String(raw.to_string())
+}
+
+/// Parses the inside of a tag: a name followed by `key=value` arguments.
+fn parse_tag_contents(contents: &str, line: usize) -> Result<(String, Map<String, Value>)> {
+    let contents = contents.trim();
+    let (name, mut rest) = contents
+        .split_once(char::is_whitespace)
+        .unwrap_or((contents, ""));
+    if name.is_empty() {
+        return Err(error(line, "it has no name"));
+    }
+
+    let mut args = Map::new();
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let (key, after_key) = rest
+            .split_once('=')
+            .ok_or_else(|| error(line, format!("the argument `{rest}` has no value")))?;
+        let after_key = after_key.trim_start();
+
+        let (value, remaining) = match after_key.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let end = after_key[1..].find(quote).ok_or_else(|| {
+                    error(
+                        line,
+                        format!(
+                            "the string for the argument `{}` is never closed",
+                            key.trim()
+                        ),
+                    )
+                })?;
+                (
+                    Value::String(after_key[1..end + 1].to_string()),
+                    &after_key[end + 2..],
+                )
+            }
+            _ => {
+                let end = after_key
+                    .find(char::is_whitespace)
+                    .unwrap_or(after_key.len());
+                (parse_value(&after_key[..end]), &after_key[end..])
+            }
+        };
+        args.insert(key.trim().to_string(), value);
+        rest = remaining;
+    }
+
+    Ok((name.to_string(), args))
+}
+
+/// Parses the tag starting at byte `start` of `markdown`, which begins with `{{<`.
+fn parse_tag(markdown: &str, start: usize) -> Result<ShortcodeTag> {
+    let line = line_of(markdown, start);
+    let inner_start = start + "{{<".len();
+    let inner_len = markdown[inner_start..]
+        .find(">}}")
+        .ok_or_else(|| error(line, "it's never closed with `>}}`"))?;
+    let inner = &markdown[inner_start..inner_start + inner_len];
+
+    let (closing, contents) = match inner.trim_start().strip_prefix('/') {
+        Some(contents) => (true, contents),
+        None => (false, inner),
+    };
+    let (name, args) = parse_tag_contents(contents, line)?;
+
+    Ok(ShortcodeTag {
+        name,
+        args,
+        closing,
+        span: start..inner_start + inner_len + ">}}".len(),
+    })
+}
+
+/// Finds the closing tag that matches `open`, accounting for nested shortcodes
+/// of the same name. Returns `None` for inline shortcodes, which have no closing tag.
+fn find_closing_tag(
+    markdown: &str,
+    open: &ShortcodeTag,
+    code_ranges: &[Range<usize>],
+) -> Result<Option<ShortcodeTag>> {
+    let mut depth = 0;
+    let mut search_from = open.span.end;
+    while let Some(found) = markdown[search_from..].find("{{<") {
+        let start = search_from + found;
+        if code_ranges.iter().any(|range| range.contains(&start))
+            || markdown[start..].starts_with("{{</*")
+        {
+            search_from = start + "{{<".len();
+            continue;
+        }
+
+        let tag = parse_tag(markdown, start)?;
+        search_from = tag.span.end;
+        if tag.name != open.name {
+            continue;
+        }
+        if !tag.closing {
+            depth += 1;
+        } else if depth == 0 {
+            return Ok(Some(tag));
+        } else {
+            depth -= 1;
+        }
+    }
+    Ok(None)
+}
+
+/// What `scan` finds in a page's Markdown.
+enum Found {
+    Shortcode(Shortcode),
+    /// An escaped shortcode, like `{{</* name */>}}`, and the `{{< name >}}`
+    /// it's written out as.
+    Escaped(String),
+}
+
+/// Finds the shortcodes in `markdown`, with their byte ranges, skipping those
+/// inside code blocks and inline code.
+fn scan(markdown: &str) -> Result<Vec<(Range<usize>, Found)>> {
+    let code_ranges = code_ranges(markdown);
+    let mut found = vec![];
+    let mut search_from = 0;
+
+    while let Some(offset) = markdown[search_from..].find("{{<") {
+        let start = search_from + offset;
+        if code_ranges.iter().any(|range| range.contains(&start)) {
+            search_from = start + "{{<".len();
+            continue;
+        }
+
+        if markdown[start..].starts_with("{{</*") {
+            let end = markdown[start..].find("*/>}}").ok_or_else(|| {
+                error(
+                    line_of(markdown, start),
+                    "the escaped shortcode is never closed with `*/>}}`",
+                )
+            })? + start;
+            let literal = format!("{{{{<{}>}}}}", &markdown[start + "{{</*".len()..end]);
+            search_from = end + "*/>}}".len();
+            found.push((start..search_from, Found::Escaped(literal)));
+            continue;
+        }
+
+        let open = parse_tag(markdown, start)?;
+        if open.closing {
+            return Err(error(
+                line_of(markdown, start),
+                format!("the closing tag `{}` has no opening tag", open.name),
+            ));
+        }
+
+        let (body, end) = match find_closing_tag(markdown, &open, &code_ranges)? {
+            Some(close) => (
+                Some(markdown[open.span.end..close.span.start].to_string()),
+                close.span.end,
+            ),
+            None => (None, open.span.end),
+        };
+        found.push((
+            start..end,
+            Found::Shortcode(Shortcode {
+                name: open.name,
+                args: open.args,
+                body,
+                body_start: open.span.end,
+            }),
+        ));
+        search_from = end;
+    }
+    Ok(found)
+}
+
+/// Returns the byte ranges of the shortcodes in `markdown`, from the start of
+/// the opening tag to the end of the closing one, including escaped ones.
+/// Cutting Markdown inside one of them would break the shortcode.
+pub(crate) fn spans(markdown: &str) -> Result<Vec<Range<usize>>> {
+    Ok(scan(markdown)?.into_iter().map(|(span, _)| span).collect())
+}
+
+/// Replaces every shortcode in `markdown` with a placeholder, returning the new
+/// Markdown, the shortcodes in placeholder order, and where the new Markdown
+/// came from.
+///
+/// Shortcodes inside code blocks and inline code are left alone, and
+/// `{{</* name */>}}` is written out literally as `{{< name >}}`.
+pub(crate) fn extract(markdown: &str) -> Result<(String, Vec<Shortcode>, SourceMap)> {
+    let mut output = String::new();
+    let mut shortcodes = vec![];
+    let mut map = SourceMap::default();
+    let mut copied_up_to = 0;
+    for (span, found) in scan(markdown)? {
+        map.copied(output.len(), copied_up_to);
+        output.push_str(&markdown[copied_up_to..span.start]);
+        map.replaced(output.len(), span.start);
+        match found {
+            Found::Escaped(literal) => output.push_str(&literal),
+            Found::Shortcode(shortcode) => {
+                output.push_str(&placeholder(shortcodes.len()));
+                shortcodes.push(shortcode);
+            }
+        }
+        copied_up_to = span.end;
+    }
+    map.copied(output.len(), copied_up_to);
+    output.push_str(&markdown[copied_up_to..]);
+    Ok((output, shortcodes, map))
+}
+
+/// Replaces the placeholders in `url` with the rendered shortcodes.
+fn substitute_url<'a>(url: CowStr<'a>, rendered: &[String]) -> CowStr<'a> {
+    if next_placeholder(&url, rendered.len()).is_none() {
+        return url;
+    }
+    let mut substituted = String::new();
+    let mut rest: &str = &url;
+    while let Some((before, index, after)) = next_placeholder(rest, rendered.len()) {
+        substituted.push_str(before);
+        substituted.push_str(&rendered[index]);
+        rest = after;
+    }
+    substituted.push_str(rest);
+    substituted.into()
+}
+
+/// Swaps the placeholders left by `extract` in the text of `events` for the
+/// rendered shortcodes, as raw HTML, so they don't end up in heading IDs or
+/// the table of contents. A placeholder alone in a paragraph replaces the
+/// whole paragraph, so block-level output isn't wrapped in `<p>`. Placeholders
+/// in link and image URLs are replaced too.
+pub(crate) fn substitute<'a>(events: Vec<Event<'a>>, rendered: &[String]) -> Vec<Event<'a>> {
+    let mut substituted = Vec::with_capacity(events.len());
+    let mut i = 0;
+    while i < events.len() {
+        if let (
+            Event::Start(Tag::Paragraph),
+            Some(Event::Text(text)),
+            Some(Event::End(TagEnd::Paragraph)),
+        ) = (&events[i], events.get(i + 1), events.get(i + 2))
+        {
+            if let Some(("", index, "")) = next_placeholder(text, rendered.len()) {
+                substituted.push(Event::Html(rendered[index].clone().into()));
+                i += 3;
+                continue;
+            }
+        }
+
+        match events[i].clone() {
+            Event::Text(text) => {
+                let mut rest: &str = &text;
+                while let Some((before, index, after)) = next_placeholder(rest, rendered.len()) {
+                    if !before.is_empty() {
+                        substituted.push(Event::Text(before.to_string().into()));
+                    }
+                    substituted.push(Event::InlineHtml(rendered[index].clone().into()));
+                    rest = after;
+                }
+                if rest.len() == text.len() {
+                    substituted.push(Event::Text(text));
+                } else if !rest.is_empty() {
+                    substituted.push(Event::Text(rest.to_string().into()));
+                }
+            }
+            Event::Start(Tag::Link {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => substituted.push(Event::Start(Tag::Link {
+                link_type,
+                dest_url: substitute_url(dest_url, rendered),
+                title,
+                id,
+            })),
+            Event::Start(Tag::Image {
+                link_type,
+                dest_url,
+                title,
+                id,
+            }) => substituted.push(Event::Start(Tag::Image {
+                link_type,
+                dest_url: substitute_url(dest_url, rendered),
+                title,
+                id,
+            })),
+            event => substituted.push(event),
+        }
+        i += 1;
+    }
+    substituted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pulldown_cmark::{html, Parser};
+
+    /// Renders `markdown` to HTML with its shortcodes replaced by `rendered`,
+    /// in order.
+    fn render(markdown: &str, rendered: &[&str]) -> String {
+        let (markdown, _, _) = extract(markdown).unwrap();
+        let rendered: Vec<String> = rendered.iter().map(|r| r.to_string()).collect();
+        let events = substitute(Parser::new(&markdown).collect(), &rendered);
+        let mut output = String::new();
+        html::push_html(&mut output, events.into_iter());
+        output
+    }
+
+    /// Returns the Markdown `extract` leaves, and each shortcode's name and body.
+    fn extracted(markdown: &str) -> (String, Vec<(String, Option<String>)>) {
+        let (output, shortcodes, _) = extract(markdown).unwrap();
+        let shortcodes = shortcodes
+            .into_iter()
+            .map(|shortcode| (shortcode.name, shortcode.body))
+            .collect();
+        (output, shortcodes)
+    }
+
+    /// Returns the line and message of the error from extracting `markdown`.
+    fn extract_error(markdown: &str) -> (usize, String) {
+        let error = extract(markdown)
+            .unwrap_err()
+            .downcast::<ShortcodeError>()
+            .unwrap();
+        (error.line, error.message)
+    }
+
+    #[test]
+    fn finds_inline_and_block_shortcodes() {
+        let (output, shortcodes) =
+            extracted("A {{< youtube id=\"x\" >}}.\n\n{{< note >}}*B*{{< /note >}}\n");
+        assert_eq!(
+            output,
+            "A pushpin-shortcode-0-end.\n\npushpin-shortcode-1-end\n"
+        );
+        assert_eq!(
+            shortcodes,
+            [
+                ("youtube".to_string(), None),
+                ("note".to_string(), Some("*B*".to_string()))
+            ]
+        );
+
+        let (_, shortcodes, _) = extract("{{< a s=\"x y\" n=2 f=1.5 b=true w=z >}}").unwrap();
+        assert_eq!(
+            Value::Object(shortcodes[0].args.clone()),
+            serde_json::json!({"s": "x y", "n": 2, "f": 1.5, "b": true, "w": "z"})
+        );
+    }
+
+    #[test]
+    fn matches_nested_shortcodes_of_the_same_name() {
+        let (output, shortcodes) = extracted(
+            "{{< box >}}a {{< box >}}b{{< /box >}} {{< b >}}{{< /box >}} {{< box >}}c{{< /box >}}",
+        );
+        assert_eq!(output, "pushpin-shortcode-0-end pushpin-shortcode-1-end");
+        assert_eq!(
+            shortcodes,
+            [
+                (
+                    "box".to_string(),
+                    Some("a {{< box >}}b{{< /box >}} {{< b >}}".to_string())
+                ),
+                ("box".to_string(), Some("c".to_string()))
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_out_escaped_shortcodes() {
+        let (output, shortcodes) = extracted("{{</* youtube id=\"x\" */>}} and {{</* /note */>}}");
+        assert_eq!(output, "{{< youtube id=\"x\" >}} and {{< /note >}}");
+        assert!(shortcodes.is_empty());
+        assert_eq!(
+            spans("a {{</* b */>}} c {{< d >}}").unwrap(),
+            [2..15, 18..27]
+        );
+    }
+
+    #[test]
+    fn leaves_shortcodes_in_code_alone() {
+        let markdown = "`{{< a >}}` and ``x ` {{< b >}}``\n\n```\n{{< c >}}\n```\n\n~~~\n{{< d\n";
+        let (output, shortcodes) = extracted(markdown);
+        assert_eq!(output, markdown);
+        assert!(shortcodes.is_empty());
+
+        // A closing tag inside code doesn't close the shortcode.
+        let (_, shortcodes) = extracted("{{< box >}}`{{< /box >}}`{{< /box >}}");
+        assert_eq!(
+            shortcodes,
+            [("box".to_string(), Some("`{{< /box >}}`".to_string()))]
+        );
+    }
+
+    #[test]
+    fn reports_broken_shortcodes_with_their_line() {
+        assert_eq!(
+            extract_error("a\n\nb {{< youtube\n"),
+            (3, "it's never closed with `>}}`".to_string())
+        );
+        assert_eq!(
+            extract_error("a\n{{</* youtube >}}"),
+            (
+                2,
+                "the escaped shortcode is never closed with `*/>}}`".to_string()
+            )
+        );
+        assert_eq!(
+            extract_error("{{< youtube id=\"x >}}"),
+            (
+                1,
+                "the string for the argument `id` is never closed".to_string()
+            )
+        );
+        assert_eq!(
+            extract_error("{{< youtube id >}}"),
+            (1, "the argument `id` has no value".to_string())
+        );
+        assert_eq!(
+            extract_error("\n{{< >}}"),
+            (2, "it has no name".to_string())
+        );
+        assert_eq!(
+            extract_error("a\n\n{{< /note >}}"),
+            (3, "the closing tag `note` has no opening tag".to_string())
+        );
+    }
+
+    #[test]
+    fn substitutes_shortcodes_in_text() {
+        assert_eq!(render("{{< b >}}\n", &["<b>B</b>"]), "<b>B</b>");
+        assert_eq!(
+            render("A {{< b >}} and {{< b >}}.\n", &["<b>1</b>", "<b>2</b>"]),
+            "<p>A <b>1</b> and <b>2</b>.</p>\n"
+        );
+        assert_eq!(
+            render("## Head {{< b >}}\n", &["<b>B</b>"]),
+            "<h2>Head <b>B</b></h2>\n"
+        );
+    }
+
+    #[test]
+    fn substitutes_shortcodes_in_urls() {
+        assert_eq!(
+            render("[home]({{< url >}}/about)\n", &["https://example.com"]),
+            "<p><a href=\"https://example.com/about\">home</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn leaves_other_text_alone() {
+        assert_eq!(
+            render("pushpin-shortcode-1-end pushpin-shortcode-x-end\n", &[]),
+            "<p>pushpin-shortcode-1-end pushpin-shortcode-x-end</p>\n"
+        );
+    }
+}
@@ -0,0 +1,263 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde_json::Value;
+use tera::Tera;
+
+use crate::current_page::template_path;
+use crate::data;
+
+/// Returns a page's Markdown file, relative to `pages/`.
+fn page_path(page: &Value) -> PathBuf {
+    let markdown_path = Path::new(page["markdown_path"].as_str().unwrap_or(""));
+    markdown_path
+        .strip_prefix("pages")
+        .unwrap_or(markdown_path)
+        .to_path_buf()
+}
+
+/// Collects the pages of `section` and its subsections, in the order the
+/// `sections` tree lists them. Sections' own `_index.md` pages are only
+/// included with `with_index`.
+fn collect_pages<'a>(section: &'a Value, with_index: bool, pages: &mut Vec<&'a Value>) {
+    if with_index && !section["index"].is_null() {
+        pages.push(&section["index"]);
+    }
+    if let Some(section_pages) = section["pages"].as_array() {
+        pages.extend(section_pages);
+    }
+    for subsection in section["subsections"].as_array().into_iter().flatten() {
+        collect_pages(subsection, with_index, pages);
+    }
+}
+
+/// Finds the section whose directory, relative to `pages/`, is `path`.
+fn find_section<'a>(section: &'a Value, path: &Path) -> Option<&'a Value> {
+    if Path::new(section["path"].as_str().unwrap_or("")) == path {
+        return Some(section);
+    }
+    section["subsections"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find_map(|subsection| find_section(subsection, path))
+}
+
+/// Returns the values of a front matter field as strings, whether it's a
+/// single value or a list, like `tags: [rust, web]`.
+fn field_values(front_matter: &Value, kind: &str) -> Vec<String> {
+    let value = |value: &Value| match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    };
+    match &front_matter[kind] {
+        Value::Array(items) => items.iter().filter_map(value).collect(),
+        other => value(other).into_iter().collect(),
+    }
+}
+
+/// Groups the pages in `sections` by the values of their `kind` front matter
+/// field, like `tags`, into a list of `{name, pages}` terms sorted by name.
+pub(crate) fn taxonomy(sections: &Value, kind: &str) -> Vec<Value> {
+    let mut pages = vec![];
+    collect_pages(sections, true, &mut pages);
+    let mut terms: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for page in pages {
+        for name in field_values(&page["front_matter"], kind) {
+            terms.entry(name).or_default().push(page.clone());
+        }
+    }
+    terms
+        .into_iter()
+        .map(|(name, pages)| serde_json::json!({ "name": name, "pages": pages }))
+        .collect()
+}
+
+/// Returns true if the page's front matter matches every condition in
+/// `filter`. A condition is either `key=value`, for a field set to `value` or
+/// to a list containing it, or just `key`, for a field that's set and isn't
+/// `false`.
+fn matches_filter(page: &Value, filter: &[&str]) -> bool {
+    filter
+        .iter()
+        .all(|condition| match condition.split_once('=') {
+            Some((key, expected)) => field_values(&page["front_matter"], key.trim())
+                .iter()
+                .any(|value| value == expected.trim()),
+            None => !matches!(
+                page["front_matter"][condition.trim()],
+                Value::Null | Value::Bool(false)
+            ),
+        })
+}
+
+/// Returns a page's value for `sort_by`: one of the page's own fields, like
+/// `title`, or else a field of its front matter.
+fn sort_key<'a>(page: &'a Value, sort_by: &str) -> Option<&'a Value> {
+    [&page[sort_by], &page["front_matter"][sort_by]]
+        .into_iter()
+        .find(|value| !value.is_null())
+}
+
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        _ => Ordering::Equal,
+    }
+}
+
+fn string_arg<'a>(
+    args: &'a HashMap<String, Value>,
+    name: &str,
+    function: &str,
+) -> tera::Result<Option<&'a str>> {
+    match args.get(name) {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(_) => Err(tera::Error::msg(format!(
+            "`{function}` needs `{name}` to be a string"
+        ))),
+    }
+}
+
+/// Registers the template functions that query the whole site, given the
+/// `sections` tree:
+///
+/// - `get_page(path=...)` returns the page at `path`, like `guides/intro.md`
+///   or `guides/intro.html`.
+/// - `get_section(path=...)` returns the section at `path`, like `guides`.
+/// - `get_taxonomy(kind=...)` groups pages by a front matter field, like
+///   `tags`, returning a list of `{name, pages}` sorted by name.
+/// - `load_data(path=...)` loads a YAML, JSON, TOML or CSV file, relative to
+///   the site root.
+/// - `pages(filter=..., section=..., sort_by=..., limit=...)` lists pages,
+///   optionally only those in a section or whose front matter matches
+///   `filter` (see `matches_filter`), sorted by a field and cut to `limit`
+///   pages.
+pub(crate) fn register_functions(tera: &mut Tera, sections: Value) {
+    let sections = Arc::new(sections);
+
+    let root = sections.clone();
+    tera.register_function("get_page", move |args: &HashMap<String, Value>| {
+        let path = template_path(args, "get_page")?;
+        let mut pages = vec![];
+        collect_pages(&root, true, &mut pages);
+        pages
+            .into_iter()
+            .find(|page| {
+                page_path(page) == path
+                    || Path::new(page["target_path"].as_str().unwrap_or("")) == path
+            })
+            .cloned()
+            .ok_or_else(|| {
+                tera::Error::msg(format!("`get_page` found no page at `{}`", path.display()))
+            })
+    });
+
+    let root = sections.clone();
+    tera.register_function("get_section", move |args: &HashMap<String, Value>| {
+        let path = template_path(args, "get_section")?;
+        find_section(&root, &path).cloned().ok_or_else(|| {
+            tera::Error::msg(format!(
+                "`get_section` found no section at `pages/{}`",
+                path.display()
+            ))
+        })
+    });
+
+    let root = sections.clone();
+    tera.register_function("get_taxonomy", move |args: &HashMap<String, Value>| {
+        let kind = string_arg(args, "kind", "get_taxonomy")?
+            .ok_or_else(|| tera::Error::msg("`get_taxonomy` needs a `kind` argument"))?;
+        Ok(Value::Array(taxonomy(&root, kind)))
+    });
+
+    tera.register_function("load_data", |args: &HashMap<String, Value>| {
+        let path = string_arg(args, "path", "load_data")?
+            .ok_or_else(|| tera::Error::msg("`load_data` needs a `path` argument"))?;
+        match data::parse_data_file(Path::new(path)) {
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => Err(tera::Error::msg(format!(
+                "`load_data` can't load `{path}`: it isn't a YAML, JSON, TOML or CSV file"
+            ))),
+            Err(e) => Err(tera::Error::msg(format!(
+                "`load_data` failed to load `{path}`: {e}"
+            ))),
+        }
+    });
+
+    let root = sections;
+    tera.register_function("pages", move |args: &HashMap<String, Value>| {
+        let section = match string_arg(args, "section", "pages")? {
+            Some(path) => {
+                let path = Path::new(path.trim_matches('/'));
+                find_section(&root, path).ok_or_else(|| {
+                    tera::Error::msg(format!(
+                        "`pages` found no section at `pages/{}`",
+                        path.display()
+                    ))
+                })?
+            }
+            None => &*root,
+        };
+        let mut pages = vec![];
+        collect_pages(section, false, &mut pages);
+
+        let filter: Vec<&str> = match args.get("filter") {
+            None => vec![],
+            Some(Value::String(condition)) => vec![condition],
+            Some(Value::Array(conditions)) => conditions
+                .iter()
+                .map(|condition| condition.as_str())
+                .collect::<Option<_>>()
+                .ok_or_else(|| tera::Error::msg("`pages` needs `filter` to be a list of strings"))?,
+            Some(_) => {
+                return Err(tera::Error::msg(
+                    "`pages` needs `filter` to be a string, like `filter=\"tags=rust\"`, or a list of them",
+                ))
+            }
+        };
+        pages.retain(|page| matches_filter(page, &filter));
+
+        // Dates sort newest first, like sections sorted by date; anything
+        // else sorts in ascending order. Pages without the field go last.
+        if let Some(sort_by) = string_arg(args, "sort_by", "pages")? {
+            fn date(value: &Value) -> Option<data::DateKey<'_>> {
+                value.as_str().and_then(data::parse_date)
+            }
+            if sort_by == "date" {
+                if let Some(bad) = pages
+                    .iter()
+                    .filter_map(|page| sort_key(page, "date"))
+                    .find(|value| date(value).is_none())
+                {
+                    return Err(tera::Error::msg(format!(
+                        "`pages` can't sort by date: {bad} isn't a date like `2024-05-05`"
+                    )));
+                }
+            }
+            pages.sort_by(|a, b| match (sort_key(a, sort_by), sort_key(b, sort_by)) {
+                (Some(a), Some(b)) if sort_by == "date" => date(b).cmp(&date(a)),
+                (Some(a), Some(b)) => compare_values(a, b),
+                (a, b) => a.is_none().cmp(&b.is_none()),
+            });
+        }
+
+        if let Some(limit) = args.get("limit") {
+            let limit = limit
+                .as_u64()
+                .ok_or_else(|| tera::Error::msg("`pages` needs `limit` to be a number"))?;
+            pages.truncate(limit as usize);
+        }
+        Ok(Value::Array(pages.into_iter().cloned().collect()))
+    });
+}
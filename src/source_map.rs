@@ -0,0 +1,145 @@
+/// Where the pieces of some rewritten Markdown came from in the Markdown it
+/// was rewritten from. `shortcodes::extract` and `admonitions::expand_fenced`
+/// rewrite a page before it's parsed, and return one of these so errors can
+/// still point at what the author wrote.
+#[derive(Debug, Default)]
+pub(crate) struct SourceMap {
+    /// In order of where they start in the rewritten Markdown.
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug)]
+struct Piece {
+    /// Where the piece starts in the rewritten Markdown.
+    output: usize,
+    /// Where it came from in the original Markdown.
+    source: usize,
+    /// Whether the piece was copied from the original as-is, so offsets
+    /// inside it map one to one, or written in place of it, so they all map
+    /// to where it came from.
+    copied: bool,
+}
+
+impl SourceMap {
+    /// Records that the rewritten Markdown continues from `output` with a copy
+    /// of the original from `source`.
+    pub(crate) fn copied(&mut self, output: usize, source: usize) {
+        self.pieces.push(Piece {
+            output,
+            source,
+            copied: true,
+        });
+    }
+
+    /// Records that the rewritten Markdown continues from `output` with text
+    /// written in place of the original at `source`.
+    pub(crate) fn replaced(&mut self, output: usize, source: usize) {
+        self.pieces.push(Piece {
+            output,
+            source,
+            copied: false,
+        });
+    }
+
+    /// Returns where the byte at `offset` in the rewritten Markdown came from
+    /// in the original.
+    fn source_offset(&self, offset: usize) -> usize {
+        let index = self.pieces.partition_point(|piece| piece.output <= offset);
+        match index.checked_sub(1).map(|index| &self.pieces[index]) {
+            Some(piece) if piece.copied => piece.source + offset - piece.output,
+            Some(piece) => piece.source,
+            None => offset,
+        }
+    }
+}
+
+/// Markdown as the author wrote it, with the maps of each rewrite it went
+/// through before being parsed, in order.
+pub(crate) struct Source<'s> {
+    text: &'s str,
+    maps: Vec<SourceMap>,
+}
+
+impl<'s> Source<'s> {
+    pub(crate) fn new(text: &'s str, maps: Vec<SourceMap>) -> Self {
+        Source { text, maps }
+    }
+
+    /// The Markdown as written.
+    pub(crate) fn text(&self) -> &'s str {
+        self.text
+    }
+
+    /// Returns the line and column, counting from 1, in the Markdown as
+    /// written, of the byte at `offset` in the Markdown that was parsed.
+    pub(crate) fn position(&self, offset: usize) -> (usize, usize) {
+        let offset = self
+            .maps
+            .iter()
+            .rev()
+            .fold(offset, |offset, map| map.source_offset(offset));
+        position(self.text, offset)
+    }
+}
+
+/// Returns the line and column, counting from 1, of the byte at `offset` in `text`.
+pub(crate) fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = text.get(..offset).unwrap_or(text);
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use pulldown_cmark::{Options, Parser};
+
+    use super::*;
+    use crate::admonitions;
+    use crate::math::{self, MathError};
+    use crate::shortcodes;
+
+    /// Returns the line and column of the first bad math in `markdown`.
+    fn math_error_position(markdown: &str) -> (usize, usize) {
+        let (extracted, _, shortcode_map) = shortcodes::extract(markdown).unwrap();
+        let (expanded, fenced_map) = admonitions::expand_fenced(&extracted);
+        let source = Source::new(markdown, vec![shortcode_map, fenced_map]);
+        let parser = Parser::new_ext(&expanded, Options::ENABLE_MATH);
+        let error = math::render_math(parser.into_offset_iter(), &source)
+            .unwrap_err()
+            .downcast::<MathError>()
+            .unwrap();
+        (error.line, error.column)
+    }
+
+    #[test]
+    fn maps_errors_back_through_rewrites() {
+        let cases = [
+            ("Some $\\frac{1}$ math.", (1, 6)),
+            (
+                "{{< figure\n  src=\"a.png\" >}}\n\nAnd {{< b >}}x{{< /b >}} $\\frac{1}$\n",
+                (4, 26),
+            ),
+            (
+                ":::note A title\nInside, $\\frac{1}$.\n:::\n\n$\\bad$\n",
+                (2, 9),
+            ),
+            (
+                ":::note\n:::tip\ntext\n:::\n:::\n\n{{< x\n>}}\n\nThen $\\bad$.\n",
+                (10, 6),
+            ),
+        ];
+        for (markdown, expected) in cases {
+            assert_eq!(math_error_position(markdown), expected, "{markdown:?}");
+        }
+    }
+
+    #[test]
+    fn finds_positions() {
+        assert_eq!(position("ab\ncdé\nf", 0), (1, 1));
+        assert_eq!(position("ab\ncdé\nf", 4), (2, 2));
+        assert_eq!(position("ab\ncdé\nf", 8), (3, 1));
+    }
+}
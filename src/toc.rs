@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+
+use pulldown_cmark::{CowStr, Event, Tag, TagEnd};
+use serde::Serialize;
+
+/// A heading in a page's table of contents. Exposed to templates as `toc`.
+#[derive(Debug, Serialize)]
+pub(crate) struct TocEntry {
+    pub(crate) level: u8,
+    pub(crate) id: String,
+    pub(crate) title: String,
+    /// The headings nested under this one.
+    pub(crate) children: Vec<TocEntry>,
+}
+
+/// Turns text into a URL-friendly slug, like `Getting Started!` into `getting-started`.
+pub(crate) fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if (c.is_whitespace() || c == '-' || c == '_') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_matches('-').to_string()
+}
+
+/// Returns `slug` if it hasn't been used yet, or else the first of `slug-1`,
+/// `slug-2`, ... that hasn't.
+fn unique_id(slug: String, used: &mut HashSet<String>) -> String {
+    let base = if slug.is_empty() {
+        "section".to_string()
+    } else {
+        slug
+    };
+
+    let mut id = base.clone();
+    let mut suffix = 1;
+    while used.contains(&id) {
+        id = format!("{base}-{suffix}");
+        suffix += 1;
+    }
+    used.insert(id.clone());
+    id
+}
+
+/// Nests a flat list of headings by level, so an `h3` after an `h2` becomes
+/// one of its children.
+fn nest(headings: Vec<TocEntry>) -> Vec<TocEntry> {
+    let mut roots: Vec<TocEntry> = vec![];
+    for heading in headings {
+        let mut siblings = &mut roots;
+        while siblings
+            .last()
+            .is_some_and(|last| last.level < heading.level)
+        {
+            siblings = &mut siblings.last_mut().unwrap().children;
+        }
+        siblings.push(heading);
+    }
+    roots
+}
+
+/// Gives every heading an `id` and collects them into a table of contents.
+///
+/// Headings without an explicit `{#id}` get one slugified from their text,
+/// de-duplicated within the page. With `heading_anchors`, each heading also gets
+/// a `<a class="heading-anchor">` permalink to itself.
+pub(crate) fn add_heading_ids<'a>(
+    events: Vec<Event<'a>>,
+    heading_anchors: bool,
+) -> (Vec<Event<'a>>, Vec<TocEntry>) {
+    // Explicit IDs are reserved up front so generated ones never collide with them.
+    let mut used_ids: HashSet<String> = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Start(Tag::Heading { id: Some(id), .. }) => Some(id.to_string()),
+            _ => None,
+        })
+        .collect();
+
+    let mut transformed = Vec::with_capacity(events.len());
+    let mut headings = vec![];
+    let mut events = events.into_iter();
+    while let Some(event) = events.next() {
+        let Event::Start(Tag::Heading {
+            level,
+            id,
+            classes,
+            attrs,
+        }) = event
+        else {
+            transformed.push(event);
+            continue;
+        };
+
+        // Gather the heading's contents, up to its end tag.
+        let mut contents = vec![];
+        let mut title = String::new();
+        for inner in events.by_ref() {
+            match &inner {
+                Event::End(TagEnd::Heading(_)) => break,
+                Event::Text(text) | Event::Code(text) => title.push_str(text),
+                _ => {}
+            }
+            contents.push(inner);
+        }
+
+        let id = match id {
+            Some(id) => id.to_string(),
+            None => unique_id(slugify(&title), &mut used_ids),
+        };
+
+        transformed.push(Event::Start(Tag::Heading {
+            level,
+            id: Some(CowStr::from(id.clone())),
+            classes,
+            attrs,
+        }));
+        transformed.extend(contents);
+        if heading_anchors {
+            transformed.push(Event::Html(
+                format!(r##"<a class="heading-anchor" href="#{id}" aria-hidden="true">#</a>"##)
+                    .into(),
+            ));
+        }
+        transformed.push(Event::End(TagEnd::Heading(level)));
+
+        headings.push(TocEntry {
+            level: level as u8,
+            id,
+            title: title.trim().to_string(),
+            children: vec![],
+        });
+    }
+
+    (transformed, nest(headings))
+}
@@ -1,4 +1,4 @@
-use notify::{Event, RecursiveMode, Watcher};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
 use std::path::Path;
 use std::sync::mpsc::channel;
 use std::thread;
@@ -59,12 +59,10 @@ where
         // Loop forever to handle events
         loop {
             match receiver.recv() {
-                Ok(event) => {
-                    for page in &event.paths {
-                        println!("🔄 Re-rendering site after update for page: {:?}", page);
-                    }
-                    callback(&event);
-                }
+                // Generating the site reads the files being watched, which
+                // isn't a change.
+                Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+                Ok(event) => callback(&event),
                 Err(e) => {
                     eprintln!("😥 internal error: {:?}. Please file a bug at https://github.com/tedbauer/pushpin/issues.", e);
                     break;